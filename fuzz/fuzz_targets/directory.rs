@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use moteria::iro::IRO;
+
+// parses the header and directory, then reads every entry the directory claims to have
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut iro) = IRO::from_slice(data) {
+        for idx in 0..iro.files.len() {
+            let _ = iro.entry_data(idx);
+        }
+    }
+});
@@ -1,12 +1,165 @@
-use moteria::iro_mmap as iro;
+use moteria::{
+    iro_compress::{LzmaAll, Rules, StoreAll},
+    iro_diff,
+    iro_extract::{ExtractOptions, UnsafeNamePolicy},
+    iro_glob::{Glob, GlobFilter},
+    iro_mmap as iro,
+    iro_repack::RepackOptions,
+    iro_stats::Stats,
+};
 
 use anyhow::Result;
 use std::path::Path;
 use clap::clap_app;
 
-fn try_extract<P: AsRef<Path>>(input_file: P) -> Result<()> {
+fn try_extract<P: AsRef<Path>>(input_file: P, options: &ExtractOptions, jobs: usize) -> Result<()> {
+    let iro = iro::open(input_file.as_ref())?;
+    Ok(iro.extract_all_parallel(options, jobs)?)
+}
+
+// returns whether the archive is intact
+fn try_verify<P: AsRef<Path>>(input_file: P) -> Result<bool> {
+    let mut iro = iro::open(input_file.as_ref())?;
+    let report = iro.verify();
+
+    for entry in report.failed() {
+        for problem in entry.problems.iter() {
+            println!("{} (entry {}): {}", entry.name, entry.entry, problem);
+        }
+    }
+    let failed = report.failed().count();
+    println!("{} entries checked, {} with problems", report.entries.len(), failed);
+
+    Ok(failed == 0)
+}
+
+fn or_unknown<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "?".to_string(), |value| value.to_string())
+}
+
+fn print_stats_table(stats: &Stats, top: usize) {
+    let ratio = or_unknown(stats.ratio().map(|ratio| format!("{:.3}", ratio)));
+    println!("{} entries, {} bytes compressed, {} bytes uncompressed, ratio {}",
+        stats.entries.len(), stats.compressed_size, stats.uncompressed_size, ratio);
+    if stats.unknown_sizes > 0 {
+        println!("{} entries with an unknown uncompressed size are left out of the uncompressed total", stats.unknown_sizes);
+    }
+
+    println!();
+    println!("{:<12} {:>8} {:>14} {:>14} {:>7}", "extension", "entries", "compressed", "uncompressed", "ratio");
+    for ext in stats.by_extension() {
+        let name = if ext.extension.is_empty() { "(none)" } else { &ext.extension };
+        let uncompressed = if ext.unknown_sizes > 0 { format!("{}+?", ext.uncompressed_size) } else { ext.uncompressed_size.to_string() };
+        let ratio = or_unknown(ext.ratio().map(|ratio| format!("{:.3}", ratio)));
+        println!("{:<12} {:>8} {:>14} {:>14} {:>7}", name, ext.count, ext.compressed_size, uncompressed, ratio);
+    }
+
+    println!();
+    println!("{:>14} {:>14} {:>7}  name", "compressed", "uncompressed", "ratio");
+    for entry in stats.largest(top) {
+        let ratio = or_unknown(entry.ratio().map(|ratio| format!("{:.3}", ratio)));
+        println!("{:>14} {:>14} {:>7}  {}", entry.compressed_size, or_unknown(entry.uncompressed_size), ratio, entry.name);
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn print_stats_json(stats: &Stats, top: usize) {
+    let null_or = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+    let sizes = |compressed: u64, uncompressed: Option<u64>, ratio: Option<f64>| {
+        format!("\"compressed_size\": {}, \"uncompressed_size\": {}, \"ratio\": {}",
+            compressed, null_or(uncompressed.map(|size| size.to_string())), null_or(ratio.map(|ratio| format!("{:.6}", ratio))))
+    };
+    let extensions: Vec<String> = stats
+        .by_extension()
+        .iter()
+        .map(|ext| {
+            let totals = sizes(ext.compressed_size, Some(ext.uncompressed_size), ext.ratio());
+            format!("{{\"extension\": {}, \"entries\": {}, {}, \"unknown_sizes\": {}}}",
+                json_string(&ext.extension), ext.count, totals, ext.unknown_sizes)
+        })
+        .collect();
+    let largest: Vec<String> = stats
+        .largest(top)
+        .iter()
+        .map(|entry| {
+            let totals = sizes(entry.compressed_size, entry.uncompressed_size, entry.ratio());
+            format!("{{\"entry\": {}, \"name\": {}, {}}}", entry.entry, json_string(&entry.name), totals)
+        })
+        .collect();
+
+    println!("{{");
+    println!("  \"entries\": {},", stats.entries.len());
+    println!("  {},", sizes(stats.compressed_size, Some(stats.uncompressed_size), stats.ratio()));
+    println!("  \"unknown_sizes\": {},", stats.unknown_sizes);
+    println!("  \"extensions\": [\n    {}\n  ],", extensions.join(",\n    "));
+    println!("  \"largest\": [\n    {}\n  ]", largest.join(",\n    "));
+    println!("}}");
+}
+
+fn try_stats<P: AsRef<Path>>(input_file: P, top: usize, json: bool) -> Result<()> {
     let mut iro = iro::open(input_file.as_ref())?;
-    iro.extract_all()
+    let stats = iro.stats();
+    if json {
+        print_stats_json(&stats, top);
+    } else {
+        print_stats_table(&stats, top);
+    }
+    Ok(())
+}
+
+fn try_diff<P: AsRef<Path>>(old_file: P, new_file: P) -> Result<()> {
+    let mut old = iro::open(old_file.as_ref())?;
+    let mut new = iro::open(new_file.as_ref())?;
+    let diff = iro_diff::diff(&mut old, &mut new)?;
+
+    for &idx in diff.removed.iter() {
+        println!("- {}", old.files[idx].name);
+    }
+    for modified in diff.modified.iter() {
+        println!("M {} ({} -> {} bytes)", modified.name, or_unknown(modified.old_size), or_unknown(modified.new_size));
+    }
+    for &idx in diff.added.iter() {
+        println!("+ {}", new.files[idx].name);
+    }
+    println!(
+        "{} added, {} removed, {} modified, {} unchanged",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.modified.len(),
+        diff.unchanged
+    );
+
+    Ok(())
+}
+
+fn try_repack<P: AsRef<Path>>(input_file: P, output_file: P, options: &RepackOptions) -> Result<()> {
+    let (input_file, output_file) = (input_file.as_ref(), output_file.as_ref());
+    // the input stays mapped while the output is written, truncating it would pull the data out from under us
+    if output_file.exists() && std::fs::canonicalize(input_file)? == std::fs::canonicalize(output_file)? {
+        anyhow::bail!("the output file can't be the input file");
+    }
+
+    let mut iro = iro::open(input_file)?;
+    let mut output = std::fs::File::create(output_file)?;
+    iro.repack(&mut output, options)?;
+    // cut off what's left of compressed data that was stored instead
+    let end = std::io::Seek::stream_position(&mut output)?;
+    Ok(output.set_len(end)?)
 }
 
 fn main() {
@@ -14,12 +167,130 @@ fn main() {
         (version: "1.0")
         (author: "mona")
         (about: "IRO extractor ripped from WIP mod library")
+        (@setting SubcommandsNegateReqs)
+        (@arg JOBS: -j --jobs +takes_value "Number of threads to extract with, defaults to one per core")
+        (@arg OUTPUT: -o --output +takes_value "Directory to extract to, defaults to the current directory")
+        (@arg UNSAFE_NAMES: --("unsafe-names") +takes_value possible_values(&["skip", "error", "rename"])
+            "What to do with entries whose names point outside the output directory, defaults to error")
+        (@arg INCLUDE: --include +takes_value +multiple number_of_values(1)
+            "Only extract entries matching this glob, e.g. battle\\*.png or **\\*.dds, can be repeated")
+        (@arg EXCLUDE: --exclude +takes_value +multiple number_of_values(1)
+            "Don't extract entries matching this glob, can be repeated")
         (@arg INPUT: +required "Sets the input file ot use")
+        (@subcommand verify =>
+            (about: "Checks that every entry of an archive is intact, exits with 1 if not")
+            (@arg INPUT: +required "Sets the input file to use")
+        )
+        (@subcommand diff =>
+            (about: "Lists the entries added, removed and modified between two archives")
+            (@arg OLD: +required "The old archive")
+            (@arg NEW: +required "The new archive")
+        )
+        (@subcommand repack =>
+            (about: "Writes a copy of an archive with its entries recompressed")
+            (@arg COMPRESSION: -c --compression +takes_value possible_values(&["store", "lzma", "auto"])
+                "Whether to store or LZMA compress entries, auto skips already compressed formats, defaults to auto")
+            (@arg PRESET: -p --preset +takes_value "LZMA preset from 0 to 9, defaults to 6")
+            (@arg UPGRADE: --upgrade "Writes V0 archives as V2")
+            (@arg INPUT: +required "Sets the input file to use")
+            (@arg OUTPUT: +required "Sets the file to write the repacked archive to")
+        )
+        (@subcommand stats =>
+            (about: "Shows the space taken up by the archive's entries, per extension and for the largest entries")
+            (@arg JSON: --json "Prints JSON instead of a table")
+            (@arg TOP: -n --top +takes_value "Number of largest entries to list, defaults to 20")
+            (@arg INPUT: +required "Sets the input file to use")
+        )
     ).get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        let input_file = matches.value_of("INPUT").unwrap();
+        match try_verify(input_file) {
+            Ok(true) => (),
+            Ok(false) => std::process::exit(1),
+            Err(err) => {
+                println!("Error while verifying: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("diff") {
+        if let Err(err) = try_diff(matches.value_of("OLD").unwrap(), matches.value_of("NEW").unwrap()) {
+            println!("Error while comparing: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("repack") {
+        let mut options = RepackOptions::default();
+        match matches.value_of("PRESET").map(str::parse::<u32>).transpose() {
+            Ok(Some(preset)) if preset <= 9 => options.lzma_preset = preset,
+            Ok(None) => (),
+            Ok(Some(preset)) => {
+                println!("Invalid LZMA preset: {}", preset);
+                std::process::exit(1);
+            }
+            Err(err) => {
+                println!("Invalid LZMA preset: {}", err);
+                std::process::exit(1);
+            }
+        }
+        match matches.value_of("COMPRESSION") {
+            Some("store") => options.set_compression_policy(StoreAll),
+            Some("lzma") => options.set_compression_policy(LzmaAll),
+            _ => options.set_compression_policy(Rules::default()),
+        }
+        options.upgrade_v0 = matches.is_present("UPGRADE");
+
+        if let Err(err) = try_repack(matches.value_of("INPUT").unwrap(), matches.value_of("OUTPUT").unwrap(), &options) {
+            println!("Error while repacking: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("stats") {
+        let input_file = matches.value_of("INPUT").unwrap();
+        let top = match matches.value_of("TOP").map(str::parse).transpose() {
+            Ok(top) => top.unwrap_or(20),
+            Err(err) => {
+                println!("Invalid number of entries: {}", err);
+                std::process::exit(1);
+            }
+        };
+        if let Err(err) = try_stats(input_file, top, matches.is_present("JSON")) {
+            println!("Error while reading stats: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let input_file = matches.value_of("INPUT").unwrap();
+    let jobs = match matches.value_of("JOBS").map(str::parse).transpose() {
+        Ok(jobs) => jobs.unwrap_or(0),
+        Err(err) => {
+            println!("Invalid number of jobs: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let mut options = ExtractOptions::new(matches.value_of("OUTPUT").unwrap_or("."));
+    options.unsafe_names = match matches.value_of("UNSAFE_NAMES") {
+        Some("skip") => UnsafeNamePolicy::Skip,
+        Some("rename") => UnsafeNamePolicy::Rename,
+        _ => UnsafeNamePolicy::Error,
+    };
+    let globs = |arg| matches.values_of(arg).map(|values| values.map(Glob::new).collect()).unwrap_or_default();
+    let filter = GlobFilter { include: globs("INCLUDE"), exclude: globs("EXCLUDE") };
+    if !filter.include.is_empty() || !filter.exclude.is_empty() {
+        options.set_filter(move |entry| filter.matches(entry));
+    }
 
-    if let Err(err) = try_extract(&input_file) {
+    if let Err(err) = try_extract(input_file, &options, jobs) {
         println!("Error while extracting: {}", err);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,148 @@
+use crate::{
+    imports::*,
+    iro::{decode_error, lzma_reader, Compression, Entry},
+    lzs::{lzs_reader, LzsReader},
+    storage::Storage,
+};
+use lzma_rust2::LzmaReader;
+
+enum Inner<'a> {
+    Stored { storage: &'a mut dyn Storage, offset: u64 },
+    Lzma(Box<LzmaReader<Box<dyn BufRead + 'a>>>),
+    Lzs(Box<LzsEntryReader<'a>>),
+}
+
+type LzsEntryReader<'a> = LzsReader<io::Chain<io::Cursor<Vec<u8>>, Box<dyn BufRead + 'a>>>;
+
+/// Reads the uncompressed data of a single entry, decompressing LZMA and LZS entries as they are read.
+///
+/// Stored entries can seek freely, compressed entries can only seek forward. LZS data doesn't record its
+/// uncompressed size, so opening an LZS entry decompresses it once to find its length.
+pub struct EntryReader<'a> {
+    inner:    Inner<'a>,
+    length:   u64,
+    position: u64,
+}
+
+impl<'a> EntryReader<'a> {
+    pub(crate) fn new(storage: &'a mut dyn Storage, entry: &Entry) -> IroResult<Self> {
+        match entry.compression {
+            Compression::None => {
+                Ok(Self { inner: Inner::Stored { storage, offset: entry.offset }, length: entry.length, position: 0 })
+            }
+            Compression::LZMA => {
+                let (lzma, length) = lzma_reader(storage.read_range(entry.offset, entry.length)?, entry)?;
+                Ok(Self { inner: Inner::Lzma(Box::new(lzma)), length, position: 0 })
+            }
+            Compression::LZS => {
+                let length = {
+                    let mut counter = lzs_reader(storage.read_range(entry.offset, entry.length)?, entry)?;
+                    io::copy(&mut counter, &mut io::sink()).map_err(|err| decode_error(entry, err))?
+                };
+
+                let lzs = lzs_reader(storage.read_range(entry.offset, entry.length)?, entry)?;
+                Ok(Self { inner: Inner::Lzs(Box::new(lzs)), length, position: 0 })
+            }
+            Compression::Unknown(_) => Err(IroError::UnsupportedEntryFlags(entry.flags.into())),
+        }
+    }
+
+    /// Uncompressed length of the entry
+    pub fn len(&self) -> u64 { self.length }
+
+    pub fn is_empty(&self) -> bool { self.length == 0 }
+
+    pub fn is_compressed(&self) -> bool { !matches!(self.inner, Inner::Stored { .. }) }
+}
+
+impl Read for EntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.length.saturating_sub(self.position);
+        let len = std::cmp::min(buf.len() as u64, remaining) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let read = match &mut self.inner {
+            Inner::Stored { storage, offset } => storage.read_range(*offset + self.position, remaining)?.read(&mut buf[..len])?,
+            Inner::Lzma(lzma) => lzma.read(&mut buf[..len])?,
+            Inner::Lzs(lzs) => lzs.read(&mut buf[..len])?,
+        };
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for EntryReader<'_> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            io::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))?;
+
+        match self.inner {
+            Inner::Stored { .. } => self.position = target,
+            _ if target >= self.position => {
+                let skip = std::cmp::min(target, self.length).saturating_sub(self.position);
+                io::copy(&mut self.by_ref().take(skip), &mut io::sink())?;
+                self.position = target;
+            }
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "compressed entries can't seek backwards"));
+            }
+        }
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        imports::*,
+        iro::{Version, IRO},
+        iro_compress::LzmaAll,
+        iro_writer::IroWriter,
+    };
+
+    fn lzma_archive(data: &[u8]) -> Vec<u8> {
+        let mut writer = IroWriter::new(Version::V2);
+        writer.set_compression_policy(LzmaAll);
+        writer.add_bytes("data.bin", data.to_vec());
+        let mut out = io::Cursor::new(Vec::new());
+        writer.write(&mut out).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn seek_compressed_forward() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let archive = lzma_archive(&data);
+        let mut iro = IRO::from_slice(&archive).unwrap();
+        let mut reader = iro.open_entry(0).unwrap();
+        assert!(reader.is_compressed());
+
+        assert_eq!(reader.seek(io::SeekFrom::Start(5000)).unwrap(), 5000);
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[5000..5004]);
+        assert_eq!(reader.seek(io::SeekFrom::Current(-4)).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn seek_compressed_past_end() {
+        let archive = lzma_archive(b"some data to compress");
+        let mut iro = IRO::from_slice(&archive).unwrap();
+        let mut reader = iro.open_entry(0).unwrap();
+        let len = reader.len();
+
+        assert_eq!(reader.seek(io::SeekFrom::Start(len + 10)).unwrap(), len + 10);
+        assert_eq!(reader.seek(io::SeekFrom::Start(len + 100)).unwrap(), len + 100);
+        assert_eq!(reader.seek(io::SeekFrom::End(5)).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        assert_eq!(reader.read(&mut [0; 16]).unwrap(), 0);
+    }
+}
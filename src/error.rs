@@ -0,0 +1,68 @@
+use crate::imports::*;
+
+#[derive(Debug)]
+pub enum IroError {
+    Io(io::Error),
+    BadSignature([u8; 4]),
+    UnsupportedVersion(u32),
+    UnsupportedArchiveFlags(u32),
+    UnsupportedEntryFlags(u32),
+    InvalidDirectory { offset: u64, reason: &'static str },
+    TruncatedDirectory { entry: usize },
+    InvalidEntry { entry: usize, reason: &'static str },
+    EntryOutOfBounds { name: String, offset: u64, length: u64, archive_size: u64 },
+    NoSuchEntry(usize),
+    NoSuchName(String),
+    UnsafeName { name: String, reason: &'static str },
+    NotAPatch,
+    NameTooLong(String),
+    ArchiveTooLarge,
+    Lzma { name: String, message: String },
+    Lzs { name: String, message: String },
+    Cancelled,
+}
+
+pub type IroResult<T> = std::result::Result<T, IroError>;
+
+impl fmt::Display for IroError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use IroError::*;
+        match self {
+            Io(err) => write!(f, "I/O error: {}", err),
+            BadSignature(sig) => write!(f, "bad IRO signature {:?}", sig),
+            UnsupportedVersion(version) => write!(f, "unsupported IRO version {:x}", version),
+            UnsupportedArchiveFlags(flags) => write!(f, "unsupported archive flags {:x}", flags),
+            UnsupportedEntryFlags(flags) => write!(f, "unsupported entry flags {:x}", flags),
+            InvalidDirectory { offset, reason } => write!(f, "invalid directory at {:#x}: {}", offset, reason),
+            TruncatedDirectory { entry } => write!(f, "directory truncated at entry {}", entry),
+            InvalidEntry { entry, reason } => write!(f, "invalid directory entry {}: {}", entry, reason),
+            EntryOutOfBounds { name, offset, length, archive_size } => write!(
+                f,
+                "entry {} ({} bytes at {:#x}) lies outside the archive ({} bytes)",
+                name, length, offset, archive_size
+            ),
+            NoSuchEntry(idx) => write!(f, "no entry with index {}", idx),
+            NoSuchName(name) => write!(f, "no entry named {}", name),
+            UnsafeName { name, reason } => write!(f, "unsafe entry name {:?}: {}", name, reason),
+            NotAPatch => write!(f, "not a patch archive"),
+            NameTooLong(name) => write!(f, "entry name too long: {}", name),
+            ArchiveTooLarge => write!(f, "archive contents exceed the offset range of the IRO version"),
+            Lzma { name, message } => write!(f, "LZMA error in {}: {}", name, message),
+            Lzs { name, message } => write!(f, "LZS error in {}: {}", name, message),
+            Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for IroError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IroError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for IroError {
+    fn from(err: io::Error) -> Self { IroError::Io(err) }
+}
@@ -1,6 +1,14 @@
-use crate::imports::*;
-
-const IRO_SIGNATURE: &[u8; 4] = b"IROS";
+use crate::{
+    entry_reader::EntryReader,
+    imports::*,
+    lzs::lzs_reader,
+    storage::{FileStorage, ReaderStorage, SharedStorage, Storage},
+};
+use lzma_rust2::LzmaReader;
+use zerocopy::{FromBytes, LayoutVerified, Unaligned};
+use std::{borrow::Cow, cell::Cell, collections::HashMap, ops::Range};
+
+pub(crate) const IRO_SIGNATURE: &[u8; 4] = b"IROS";
 
 #[derive(Debug, Clone)]
 pub struct Entry {
@@ -8,39 +16,156 @@ pub struct Entry {
     pub length:      u64,
     pub name:        String,
     pub compression: Compression,
+    /// The record's flags as stored, including bits this library doesn't know about
+    pub flags:       EntryFlags,
+}
+
+pub(crate) const ARCHIVE_FLAG_PATCH: u32 = 0x1;
+// the low byte of an entry's flags holds its compression, 7th Heaven doesn't use the rest
+const COMPRESSION_FLAGS: u32 = 0xff;
+
+/// The control entry 7th Heaven's patch archives list deleted files in, as UTF-16 text with one name per line.
+/// See `IrosArc.ApplyPatch` in 7th Heaven's `_7thWrapperLib`.
+pub const PATCH_DELETED_ENTRY: &str = "%IrosPatch:Deleted";
+
+/// Entries whose name starts with `%` hold instructions for 7th Heaven rather than game files
+pub fn is_control_name(name: &str) -> bool { name.starts_with('%') }
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Full,
+    Patch,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PatchAction {
+    /// Add the entry's data to the base archive, replacing any entry of the same name
+    Replace,
+    /// Remove the entry of the same name from the base archive
+    Remove,
+}
+
+/// One operation of a patch archive, see [`IRO::patch_entries`]
+#[derive(Debug, Clone)]
+pub struct PatchEntry {
+    pub name:   String,
+    pub action: PatchAction,
+    /// Index into `IRO.files` of the new data, `None` for removals
+    pub entry:  Option<usize>,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Compression {
     None,
+    /// FF7's own LZSS scheme, as used by `.lzs` files and some older packers
+    LZS,
     LZMA,
+    /// A compression byte this library can't decode, kept so the entry can still be copied as is
+    Unknown(u8),
 }
 
-impl From<u32> for Compression {
-    fn from(other: u32) -> Self {
+impl From<u8> for Compression {
+    fn from(other: u8) -> Self {
         use Compression::*;
         match other {
             0 => None,
+            1 => LZS,
             2 => LZMA,
-            unk => unimplemented!("Unknown compression type {:x}", unk),
+            unk => Unknown(unk),
         }
     }
 }
 
-pub struct IRO {
-    reader:    io::BufReader<fs::File>,
-    pub files: Vec<Entry>,
+impl From<Compression> for u32 {
+    fn from(other: Compression) -> u32 {
+        match other {
+            Compression::None => 0,
+            Compression::LZS => 1,
+            Compression::LZMA => 2,
+            Compression::Unknown(unk) => unk as u32,
+        }
+    }
 }
 
-enum Version {
+/// The flags of a directory record: the compression in the low byte and bits of no known meaning above it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct EntryFlags(pub u32);
+
+impl EntryFlags {
+    pub fn compression(self) -> Compression { Compression::from((self.0 & COMPRESSION_FLAGS) as u8) }
+
+    /// The same flags with the compression byte replaced
+    pub fn with_compression(self, compression: Compression) -> Self {
+        Self(self.0 & !COMPRESSION_FLAGS | u32::from(compression))
+    }
+
+    /// Bits above the compression byte, kept so they round-trip
+    pub fn unknown_bits(self) -> u32 { self.0 & !COMPRESSION_FLAGS }
+}
+
+impl From<u32> for EntryFlags {
+    fn from(other: u32) -> Self { Self(other) }
+}
+
+impl From<EntryFlags> for u32 {
+    fn from(other: EntryFlags) -> u32 { other.0 }
+}
+
+/// An opened archive, reading entry data from `S` on demand
+pub struct IRO<S: Storage = FileStorage> {
+    storage:     S,
+    pub version: Version,
+    pub kind:    ArchiveKind,
+    pub files:   Vec<Entry>,
+    /// Directory records that are larger than their name and fields need
+    pub trailing_data: Vec<TrailingData>,
+    // where the directory records were read from
+    directory:   Range<u64>,
+    // normalized name -> index into `files`, built on open
+    index:       HashMap<String, usize>,
+}
+
+/// Limits applied while reading the directory of an untrusted archive
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    /// Directory records larger than this are rejected. Records can't exceed `u16::MAX` bytes, so the default
+    /// accepts everything.
+    pub max_entry_size: usize,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self { Self { max_entry_size: u16::MAX as usize } }
+}
+
+/// Bytes at the end of a directory record that the format doesn't account for, kept for inspection
+#[derive(Debug, Clone)]
+pub struct TrailingData {
+    pub entry: usize,
+    pub data:  Vec<u8>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Version {
     V0,
     V1,
     V2,
 }
 
+impl Version {
+    pub(crate) fn raw(self) -> u32 {
+        match self {
+            Version::V0 => 0x10000,
+            Version::V1 => 0x10001,
+            Version::V2 => 0x10002,
+        }
+    }
+
+    pub(crate) fn has_wide_offsets(self) -> bool { self != Version::V0 }
+}
+
 #[repr(C)]
-#[derive(Debug)]
-struct RawHeader {
+#[derive(Debug, FromBytes, Unaligned)]
+pub(crate) struct RawHeader {
     signature:     [u8; 4],
     version:       U32<LE>,
     archive_flags: U32<LE>,
@@ -48,22 +173,22 @@ struct RawHeader {
     num_entries:   U32<LE>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, FromBytes, Unaligned)]
 #[repr(C)]
-struct RawEntryStart {
+pub(crate) struct RawEntryStart {
     entry_size: U16<LE>,
     name_size:  U16<LE>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, FromBytes, Unaligned)]
 #[repr(C)]
-struct RawEntryEnd<OFFT: fmt::Debug> {
+pub(crate) struct RawEntryEnd<OFFT: fmt::Debug> {
     flags:  U32<LE>,
     offset: OFFT,
     length: U32<LE>,
 }
 
-trait AsU64: Copy {
+trait AsU64: Copy + FromBytes + Unaligned {
     fn as_u64(self) -> u64;
 }
 
@@ -74,191 +199,481 @@ impl AsU64 for U64<LE> {
     fn as_u64(self) -> u64 { self.get() }
 }
 
-fn reinterpret<T: Sized>(from: &[u8]) -> &T {
-    let size = size_of::<T>();
-    assert!(from.len() >= size);
+// the raw layouts are all unaligned, so this only has to check that `from` is long enough
+fn parse_prefix<T: FromBytes + Unaligned>(from: &[u8]) -> Option<&T> {
+    LayoutVerified::<_, T>::new_unaligned_from_prefix(from).map(|(layout, _)| layout.into_ref())
+}
+
+#[repr(C)]
+#[derive(FromBytes, Unaligned)]
+struct LzmaHeader {
+    unpacked_size:     U32<LE>,
+    properties_length: U32<LE>,
+}
+
+/// Turns an error from decompressing `entry` into an error naming it. Anything going wrong in LZMA data is an
+/// [`IroError::Lzma`], LZS data can only be cut short.
+pub(crate) fn decode_error(entry: &Entry, err: io::Error) -> IroError {
+    match entry.compression {
+        Compression::LZMA => IroError::Lzma { name: entry.name.clone(), message: err.to_string() },
+        Compression::LZS if err.kind() == io::ErrorKind::UnexpectedEof => {
+            IroError::Lzs { name: entry.name.clone(), message: err.to_string() }
+        }
+        _ => IroError::Io(err),
+    }
+}
+
+fn lzma_error(entry: &Entry, message: String) -> IroError {
+    decode_error(entry, io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Reads the IRO specific header in front of the LZMA properties, returning the unpacked size
+pub(crate) fn read_lzma_header<R: Read>(mut reader: R, entry: &Entry) -> IroResult<u32> {
+    if entry.length < size_of::<LzmaHeader>() as u64 + 5 {
+        return Err(lzma_error(entry, "entry too short for LZMA header".to_string()));
+    }
+    let mut header_buf: [u8; size_of::<LzmaHeader>()] = [0; size_of::<LzmaHeader>()];
+    reader.read_exact(&mut header_buf[..])?;
+    let header: &LzmaHeader = parse_prefix(&header_buf).unwrap();
+    if header.properties_length.get() != 5 {
+        return Err(lzma_error(entry, format!("unexpected properties length {}", header.properties_length.get())));
+    }
+
+    Ok(header.unpacked_size.get())
+}
+
+/// Largest dictionary an LZMA entry may need. The decoder allocates the smaller of the dictionary and unpacked sizes
+/// up front, both come from the untrusted header. The presets 7th Heaven packs with use at most 64 MiB.
+const MAX_LZMA_DICT_SIZE: u64 = 256 << 20;
+
+/// Sets up a decoder for an LZMA entry, returning it along with the unpacked size from the entry's header
+pub(crate) fn lzma_reader<R: Read>(mut reader: R, entry: &Entry) -> IroResult<(LzmaReader<R>, u64)> {
+    let unpacked_size = read_lzma_header(&mut reader, entry)? as u64;
+    let mut properties = [0u8; 5];
+    reader.read_exact(&mut properties)?;
+    let dict_size = u32::from_le_bytes([properties[1], properties[2], properties[3], properties[4]]);
+    let needed = std::cmp::min(dict_size as u64, unpacked_size);
+    if needed > MAX_LZMA_DICT_SIZE {
+        return Err(lzma_error(entry, format!("dictionary of {} bytes exceeds the limit of {}", needed, MAX_LZMA_DICT_SIZE)));
+    }
+
+    // The header holds the exact unpacked size, there is no off-by-one. Some packers end the stream with an end
+    // marker and some don't, decoding to the declared size handles both. A marker before that size is an error.
+    let lzma = LzmaReader::new_with_props(reader, unpacked_size, properties[0], dict_size, None)
+        .map_err(|err| decode_error(entry, err))?;
+    Ok((lzma, unpacked_size))
+}
+
+/// Counts the bytes passed through to `inner`
+pub(crate) struct CountingWriter<W> {
+    inner:     W,
+    pub count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self { Self { inner, count: 0 } }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// Counts the bytes taken from `inner` into a cell that can be read while the reader is in use
+pub(crate) struct CountingReader<'c, R> {
+    inner: R,
+    count: &'c Cell<u64>,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.set(self.count.get() + read as u64);
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> { self.inner.fill_buf() }
 
-    unsafe { &*(from.as_ptr() as *const T) }
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.count.set(self.count.get() + amt as u64);
+    }
 }
 
-fn reinterpret_slice<T: Sized>(from: &[u8], len: usize) -> &[T] {
-    let size = size_of::<T>() * len;
-    assert!(from.len() >= size);
+pub fn open<P: AsRef<path::Path>>(path: P) -> IroResult<IRO> { IRO::from_storage(FileStorage::open(path.as_ref())?) }
 
-    unsafe { std::slice::from_raw_parts(from.as_ptr() as *const T, len) }
+pub fn open_with_options<P: AsRef<path::Path>>(path: P, options: &ReadOptions) -> IroResult<IRO> {
+    IRO::from_storage_with_options(FileStorage::open(path.as_ref())?, options)
 }
 
-pub fn open<P: AsRef<path::Path>>(path: P) -> Result<IRO> { IRO::open(path.as_ref()) }
+/// The form 7th Heaven and the game compare entry names in: lowercase with `\` separators
+pub fn normalize_name(name: &str) -> String { name.replace('/', "\\").to_lowercase() }
 
-impl IRO {
-    fn open(path: &path::Path) -> Result<Self> {
-        // println!("Mod: {}", path.display());
-        let file = fs::File::open(path)?;
-        let mut reader = io::BufReader::with_capacity(132768, file);
+/// Extension of the last component of an entry name, without the dot. A leading dot doesn't start one, `.png` has
+/// none.
+pub fn name_extension(name: &str) -> Option<&str> {
+    let file_name = name.rsplit(['\\', '/']).next()?;
+    match file_name.rfind('.') {
+        Some(dot) if dot > 0 => Some(&file_name[dot + 1..]),
+        _ => None,
+    }
+}
+
+impl<R: Read + Seek> IRO<ReaderStorage<R>> {
+    /// Reads an archive from a seekable stream, e.g. an IRO inside a zip file or an `io::Cursor` over a buffer
+    pub fn from_reader(reader: R) -> IroResult<Self> { Self::from_storage(ReaderStorage::new(reader)?) }
+}
+
+impl<'a> IRO<&'a [u8]> {
+    pub fn from_slice(data: &'a [u8]) -> IroResult<Self> { Self::from_storage(data) }
+}
 
-        // let mut base = path.file_stem().unwrap().to_string_lossy().to_string();
-        // base.push(path::MAIN_SEPARATOR);
-        let base = String::from("");
+impl<S: Storage> IRO<S> {
+    pub fn from_storage(storage: S) -> IroResult<Self> { Self::from_storage_with_options(storage, &ReadOptions::default()) }
 
-        let files = Self::read_header(&base, &mut reader)?;
+    pub fn from_storage_with_options(mut storage: S, options: &ReadOptions) -> IroResult<Self> {
+        let (version, kind, files, trailing_data, directory) = Self::read_header(&mut storage, options)?;
+        // like 7th Heaven, a later entry with the same name shadows an earlier one
+        let index = files.iter().enumerate().map(|(idx, entry)| (normalize_name(&entry.name), idx)).collect();
 
-        Ok(Self { reader, files })
+        Ok(Self { storage, version, kind, files, trailing_data, directory, index })
     }
 
-    fn read_header<R: io::BufRead + io::Seek>(base: &str, reader: &mut R) -> Result<Vec<Entry>> {
+    /// Index of the entry named `path`, compared case-insensitively with either separator
+    pub fn find(&self, path: &str) -> Option<usize> { self.index.get(&normalize_name(path)).copied() }
+
+    pub fn contains(&self, path: &str) -> bool { self.find(path).is_some() }
+
+    pub fn into_storage(self) -> S { self.storage }
+
+    pub fn archive_size(&self) -> u64 { self.storage.size() }
+
+    /// Where the directory records lie in the archive
+    pub fn directory_range(&self) -> Range<u64> { self.directory.clone() }
+
+    #[allow(clippy::type_complexity)]
+    fn read_header(
+        storage: &mut S, options: &ReadOptions,
+    ) -> IroResult<(Version, ArchiveKind, Vec<Entry>, Vec<TrailingData>, Range<u64>)> {
+        let header_size = size_of::<RawHeader>() as u64;
+        if storage.size() < header_size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
         let num_entries;
         let version: Version;
+        let kind;
+        let directory;
         {
-            let buf = reader.fill_buf()?;
-            let header: &RawHeader = reinterpret(buf);
-            assert_eq!(&header.signature, IRO_SIGNATURE);
-            // println!("{:?}", header);
-            if header.archive_flags.get() != 0 {
-                unimplemented!("Patch archive not supported");
+            let mut header_buf = [0u8; size_of::<RawHeader>()];
+            storage.read_range(0, header_size)?.read_exact(&mut header_buf)?;
+            let header: &RawHeader = parse_prefix(&header_buf).unwrap();
+            if &header.signature != IRO_SIGNATURE {
+                return Err(IroError::BadSignature(header.signature));
             }
+            // println!("{:?}", header);
+            kind = match header.archive_flags.get() {
+                0 => ArchiveKind::Full,
+                ARCHIVE_FLAG_PATCH => ArchiveKind::Patch,
+                unk => return Err(IroError::UnsupportedArchiveFlags(unk)),
+            };
             version = match header.version.get() {
                 0x10000 => Version::V0,
                 0x10001 => Version::V1,
                 0x10002 => Version::V2,
-                unk => unimplemented!("Unsupported version {:x}", unk),
+                unk => return Err(IroError::UnsupportedVersion(unk)),
             };
             num_entries = header.num_entries.get() as usize;
+            directory = header.directory.get() as u64;
+        }
+
+        let invalid_directory = |reason| IroError::InvalidDirectory { offset: directory, reason };
+        if directory < header_size {
+            return Err(invalid_directory("overlaps the header"));
+        }
+        if directory > storage.size() {
+            return Err(invalid_directory("starts past the end of the archive"));
+        }
+        let directory_size = storage.size() - directory;
+        let min_record_size = match version {
+            Version::V0 => size_of::<RawEntryStart>() + size_of::<RawEntryEnd<U32<LE>>>(),
+            Version::V1 | Version::V2 => size_of::<RawEntryStart>() + size_of::<RawEntryEnd<U64<LE>>>(),
+        } as u64;
+        if num_entries as u64 * min_record_size > directory_size {
+            return Err(invalid_directory("too small for the number of entries"));
         }
-        reader.consume(size_of::<RawHeader>());
 
+        let mut reader = storage.read_range(directory, directory_size)?;
         let mut entries = Vec::with_capacity(num_entries);
+        let mut trailing_data = Vec::new();
+        let mut directory_end = directory;
 
-        for _ in 0..num_entries {
-            match version {
-                Version::V0 => entries.push(Self::read_entry::<_, U32<LE>>(base, reader)?),
-                Version::V1 | Version::V2 => entries.push(Self::read_entry::<_, U64<LE>>(base, reader)?),
+        for idx in 0..num_entries {
+            let (entry, trailing, record_size) = match version {
+                Version::V0 => Self::read_entry::<U32<LE>>(&mut reader, idx, options)?,
+                Version::V1 | Version::V2 => Self::read_entry::<U64<LE>>(&mut reader, idx, options)?,
+            };
+            if !trailing.is_empty() {
+                trailing_data.push(TrailingData { entry: idx, data: trailing });
             }
+            entries.push(entry);
+            directory_end += record_size as u64;
         }
-        Ok(entries)
+        Ok((version, kind, entries, trailing_data, directory..directory_end))
     }
 
-    fn read_entry<R: io::BufRead + io::Seek, OFFT: fmt::Debug + AsU64>(base: &str, reader: &mut R) -> Result<Entry> {
-        // let mut real_buf: Vec<u8> = Vec::with_capacity(0);
-        let buf = {
-            let mut buf = reader.fill_buf()?;
-            if buf.len() < 1000 {
-                // a shameful hack to refill buffer
-                reader.seek(io::SeekFrom::Current(0))?;
-                buf = reader.fill_buf()?;
-            }
-            assert!(buf.len() >= 1000);
-            buf
+    fn read_entry<OFFT: fmt::Debug + AsU64>(
+        reader: &mut dyn BufRead, idx: usize, options: &ReadOptions,
+    ) -> IroResult<(Entry, Vec<u8>, usize)> {
+        let truncated = |err: io::Error| match err.kind() {
+            io::ErrorKind::UnexpectedEof => IroError::TruncatedDirectory { entry: idx },
+            _ => IroError::Io(err),
         };
-        let start: &RawEntryStart = reinterpret(buf);
+
+        let mut start_buf = [0u8; size_of::<RawEntryStart>()];
+        reader.read_exact(&mut start_buf).map_err(truncated)?;
+        let start: &RawEntryStart = parse_prefix(&start_buf).unwrap();
         let entry_size = start.entry_size.get() as usize;
         let name_size: usize = start.name_size.get() as usize;
-        assert!(entry_size < 1000);
-        assert_eq!(name_size % size_of::<u16>(), 0);
+        if entry_size > options.max_entry_size {
+            return Err(IroError::InvalidEntry { entry: idx, reason: "entry size too large" });
+        }
+        if !name_size.is_multiple_of(size_of::<u16>()) {
+            return Err(IroError::InvalidEntry { entry: idx, reason: "odd name size" });
+        }
 
         let end_size = size_of::<RawEntryEnd<OFFT>>();
-        // fails sometimes?
-        // assert_eq!(entry_size, name_size + size_of::<RawEntryStart>() +
-        // size_of::<RawEntryEnd>());
-        assert!(entry_size >= name_size + size_of::<RawEntryStart>() + end_size);
+        // some packers write records larger than their contents, the extra bytes are returned as trailing data
+        if entry_size < name_size + size_of::<RawEntryStart>() + end_size {
+            return Err(IroError::InvalidEntry { entry: idx, reason: "entry size too small" });
+        }
 
-        // if buf.len() < entry_size {
-        //     real_buf.resize_with(entry_size, || 0);
-        //     reader.read_exact(real_buf.as_mut_slice())?;
-        //     buf = real_buf.as_slice();
-        // }
+        let mut buf = vec![0u8; entry_size - size_of::<RawEntryStart>()];
+        reader.read_exact(&mut buf).map_err(truncated)?;
 
-        assert!(entry_size <= buf.len());
-        let buf = &buf[..entry_size][size_of::<RawEntryStart>()..];
-        let name_str = UStr::<u16>::from_slice(reinterpret_slice(buf, name_size / size_of::<u16>())).to_string_lossy();
+        let name_units: Vec<u16> = buf[..name_size].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        let name = UStr::<u16>::from_slice(&name_units).to_string_lossy();
 
-        let mut name = base.to_string();
-        name.push_str(&name_str);
+        let end: &RawEntryEnd<OFFT> = parse_prefix(&buf[name_size..]).unwrap();
+        // unknown bits are kept rather than rejected, an entry that can't be decoded can still be listed and copied
+        let flags = EntryFlags(end.flags.get());
 
-        let buf = &buf[name_size..];
+        let ret = Entry {
+            name,
+            offset: end.offset.as_u64(),
+            length: end.length.as_u64(),
+            compression: flags.compression(),
+            flags,
+        };
+        let trailing = buf[name_size + end_size..].to_vec();
 
-        let end: &RawEntryEnd<OFFT> = reinterpret(buf);
-        let compression = end.flags.get().into();
+        Ok((ret, trailing, entry_size))
+    }
 
-        let ret = Entry { name, offset: end.offset.as_u64(), length: end.length.as_u64(), compression };
+    fn extract_lzma<W: Write, R: BufRead>(reader: R, writer: W, entry: &Entry) -> IroResult<()> {
+        let (mut lzma, unpacked_size) = lzma_reader(reader, entry)?;
+        let mut writer = CountingWriter::new(writer);
+        let mut buf = [0u8; 16 << 10];
+        loop {
+            // errors from the decoder describe the stream, errors from the writer are passed on as they are
+            let read = match lzma.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(lzma_error(entry, format!("compressed data ends before {} bytes were unpacked", unpacked_size)));
+                }
+                Err(err) => return Err(decode_error(entry, err)),
+            };
+            writer.write_all(&buf[..read])?;
+        }
+        if writer.count != unpacked_size {
+            return Err(lzma_error(entry, format!("unpacked to {} bytes instead of {}", writer.count, unpacked_size)));
+        }
 
-        // if real_buf.is_empty() {
-        reader.consume(entry_size);
-        // }
-        Ok(ret)
+        Ok(())
     }
 
-    fn extract_lzma<W: Write, R: io::BufRead>(mut reader: R, mut writer: W, length: u64) -> Result<()> {
-        #[repr(C)]
-        struct LzmaHeader {
-            unpacked_size:     U32<LE>,
-            properties_length: U32<LE>,
-        };
+    fn extract_lzs<W: Write, R: BufRead>(reader: R, mut writer: W, entry: &Entry) -> IroResult<()> {
+        let mut lzs = lzs_reader(reader, entry)?;
+        io::copy(&mut lzs, &mut writer).map_err(|err| decode_error(entry, err))?;
 
-        let mut header_buf: [u8; size_of::<LzmaHeader>()] = [0; size_of::<LzmaHeader>()];
-        reader.read_exact(&mut header_buf[..])?;
-        let header: &LzmaHeader = reinterpret(&header_buf);
-        assert_eq!(header.properties_length.get(), 5);
+        Ok(())
+    }
 
-        let compressed_length = length - (size_of::<LzmaHeader>() as u64);
+    fn extract_direct<W: Write, R: BufRead>(mut reader: R, mut writer: W, length: u64) -> IroResult<()> {
+        if io::copy(&mut reader, &mut writer)? != length {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
 
-        // FIXME: wtf
-        let uncompressed_length = header.unpacked_size.get();
-        // let uncompressed_length = header.unpacked_size.get() - 1;
+        Ok(())
+    }
 
-        let mut buf: Vec<u8> = Vec::with_capacity(compressed_length as usize);
-        buf.resize_with(buf.capacity(), || 0);
-        reader.read_exact(buf.as_mut_slice())?;
-        let mut cur = io::Cursor::new(buf);
+    fn entry(&self, entry_idx: usize) -> IroResult<&Entry> {
+        let entry = self.files.get(entry_idx).ok_or(IroError::NoSuchEntry(entry_idx))?;
+        let size = self.storage.size();
+        match entry.offset.checked_add(entry.length) {
+            Some(end) if end <= size => Ok(entry),
+            _ => Err(IroError::EntryOutOfBounds {
+                name:         entry.name.clone(),
+                offset:       entry.offset,
+                length:       entry.length,
+                archive_size: size,
+            }),
+        }
+    }
 
-        let opts =
-            lzma_rs::decompress::Options { unpacked_size: lzma_rs::decompress::UnpackedSize::UseProvided(Some(uncompressed_length as _)) };
-        lzma_rs::lzma_decompress_with_options(&mut cur, &mut writer, &opts).map_err(|err| anyhow!("{:?}", err))?;
+    fn decode<W: Write, R: BufRead>(reader: R, writer: W, entry: &Entry) -> IroResult<()> {
+        match entry.compression {
+            Compression::None => Self::extract_direct(reader, writer, entry.length),
+            Compression::LZS => Self::extract_lzs(reader, writer, entry),
+            Compression::LZMA => Self::extract_lzma(reader, writer, entry),
+            Compression::Unknown(_) => Err(IroError::UnsupportedEntryFlags(entry.flags.into())),
+        }
+    }
 
-        Ok(())
+    /// Like [`IRO::extract_to`], counting the stored bytes read into `read` as they are decompressed
+    pub(crate) fn extract_counting<W: Write>(&mut self, writer: W, entry_idx: usize, read: &Cell<u64>) -> IroResult<()> {
+        let entry = self.entry(entry_idx)?.clone();
+        let reader = self.storage.read_range(entry.offset, entry.length)?;
+        Self::decode(CountingReader { inner: reader, count: read }, writer, &entry)
     }
 
-    fn extract_direct<W: Write, R: io::BufRead>(mut reader: R, mut writer: W, length: u64) -> Result<()> {
-        let mut buf: [u8; 100_000] = [0; 100000];
-        let mut remain = length as usize;
+    fn extract_to_inner<W: Write>(&mut self, writer: W, entry: &Entry) -> IroResult<()> {
+        let reader = self.storage.read_range(entry.offset, entry.length)?;
+        Self::decode(reader, writer, entry)
+    }
 
-        while remain > 0 {
-            let buf_len = std::cmp::min(buf.len(), remain);
-            let read = reader.read(&mut buf[..buf_len])?;
+    /// Borrows a stored entry's data straight from memory-backed storage (mmap, slices), without copying.
+    ///
+    /// Returns `None` for compressed entries, storage that isn't in memory and invalid indices.
+    pub fn entry_bytes(&self, entry_idx: usize) -> Option<&[u8]> {
+        let entry = self.entry(entry_idx).ok()?;
+        if entry.compression != Compression::None {
+            return None;
+        }
+        let data = self.storage.as_bytes()?;
+        data.get(entry.offset as usize..(entry.offset + entry.length) as usize)
+    }
 
-            let read_buf = &buf[..read];
-            writer.write_all(&read_buf)?;
-            remain -= read;
+    /// The entry's uncompressed data, borrowed if [`entry_bytes`](Self::entry_bytes) can provide it and read or
+    /// decompressed into a buffer otherwise
+    pub fn entry_data(&mut self, entry_idx: usize) -> IroResult<Cow<'_, [u8]>> {
+        if self.entry_bytes(entry_idx).is_some() {
+            return Ok(Cow::Borrowed(self.entry_bytes(entry_idx).unwrap()));
         }
 
-        Ok(())
+        let mut reader = self.open_entry(entry_idx)?;
+        // don't trust the LZMA header for the allocation, a corrupt one can claim up to 4 GiB
+        let mut data = Vec::with_capacity(std::cmp::min(reader.len(), 64 << 20) as usize);
+        reader.read_to_end(&mut data)?;
+        Ok(Cow::Owned(data))
     }
 
-    fn extract_to_inner<W: Write>(&mut self, writer: W, length: u64, offset: u64, compression: Compression) -> Result<()> {
-        self.reader.seek(io::SeekFrom::Start(offset))?;
+    /// Opens a streaming reader over the entry's uncompressed data
+    pub fn open_entry(&mut self, entry_idx: usize) -> IroResult<EntryReader<'_>> {
+        let entry = self.entry(entry_idx)?.clone();
+        EntryReader::new(&mut self.storage, &entry)
+    }
 
-        match compression {
-            Compression::None => Self::extract_direct(&mut self.reader, writer, length),
-            Compression::LZMA => Self::extract_lzma(&mut self.reader, writer, length),
+    /// Size of the entry's data after decompression, read from the LZMA header for LZMA entries. LZS has no such
+    /// header, those entries are decompressed to count their size.
+    pub fn unpacked_size(&mut self, entry_idx: usize) -> IroResult<u64> {
+        let entry = self.entry(entry_idx)?.clone();
+        match entry.compression {
+            Compression::None => Ok(entry.length),
+            Compression::LZMA => {
+                let reader = self.storage.read_range(entry.offset, entry.length)?;
+                Ok(read_lzma_header(reader, &entry)? as u64)
+            }
+            Compression::LZS => {
+                let mut counter = CountingWriter::new(io::sink());
+                self.extract_to_inner(&mut counter, &entry)?;
+                Ok(counter.count)
+            }
+            Compression::Unknown(_) => Err(IroError::UnsupportedEntryFlags(entry.flags.into())),
         }
     }
 
-    pub fn extract_to<W: Write>(&mut self, mut writer: W, entry_idx: usize) -> Result<()> {
-        let Entry { length, offset, compression, .. } = &self.files[entry_idx].clone();
-        self.extract_to_inner(&mut writer, *length, *offset, *compression)
+    /// Copies the entry's stored bytes without decompressing them
+    pub fn copy_raw_to<W: Write>(&mut self, mut writer: W, entry_idx: usize) -> IroResult<()> {
+        let entry = self.entry(entry_idx)?.clone();
+        let reader = self.storage.read_range(entry.offset, entry.length)?;
+        Self::extract_direct(reader, &mut writer, entry.length)
     }
 
-    pub fn extract(&mut self, entry_idx: usize) -> Result<()> {
-        let Entry { length, offset, compression, name } = &self.files[entry_idx].clone();
-        let path: &path::Path = name.as_ref();
-        fs::create_dir_all(path.parent().unwrap())?;
-        let mut writer = io::BufWriter::new(fs::File::create(path)?);
-        self.extract_to_inner(&mut writer, *length, *offset, *compression)
+    /// Reads the entry's stored bytes without decompressing them
+    pub(crate) fn raw_reader(&mut self, entry_idx: usize) -> IroResult<Box<dyn BufRead + '_>> {
+        let entry = self.entry(entry_idx)?.clone();
+        Ok(self.storage.read_range(entry.offset, entry.length)?)
     }
 
-    pub fn extract_all(&mut self) -> Result<()> {
-        for idx in 0..self.files.len() {
-            self.extract(idx)?;
+    pub fn extract_to<W: Write>(&mut self, mut writer: W, entry_idx: usize) -> IroResult<()> {
+        let entry = self.entry(entry_idx)?.clone();
+        self.extract_to_inner(&mut writer, &entry)
+    }
+
+    pub fn extract_by_name<W: Write>(&mut self, path: &str, writer: W) -> IroResult<()> {
+        let entry_idx = self.find(path).ok_or_else(|| IroError::NoSuchName(path.to_string()))?;
+        self.extract_to(writer, entry_idx)
+    }
+
+    /// Names listed in the patch's [`PATCH_DELETED_ENTRY`], empty if it has none
+    pub fn patch_deletions(&mut self) -> IroResult<Vec<String>> {
+        let entry_idx = match self.find(PATCH_DELETED_ENTRY) {
+            Some(entry_idx) => entry_idx,
+            None => return Ok(Vec::new()),
+        };
+        let data = self.entry_data(entry_idx)?;
+        let units: Vec<u16> = data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        let names = UStr::<u16>::from_slice(&units).to_string_lossy();
+
+        Ok(names.split('\n').filter(|name| !name.is_empty()).map(str::to_string).collect())
+    }
+
+    /// The operations of a patch archive, empty for full archives: a removal for every name in the deletion list,
+    /// then a replacement for every other entry in directory order. 7th Heaven applies the removals first, so a
+    /// name that is both removed and replaced ends up replaced.
+    pub fn patch_entries(&mut self) -> IroResult<Vec<PatchEntry>> {
+        if self.kind != ArchiveKind::Patch {
+            return Ok(Vec::new());
         }
-        Ok(())
+
+        let mut operations: Vec<PatchEntry> = self
+            .patch_deletions()?
+            .into_iter()
+            .map(|name| PatchEntry { name, action: PatchAction::Remove, entry: None })
+            .collect();
+        for (idx, entry) in self.files.iter().enumerate() {
+            // shadowed duplicates never reach the base archive
+            if !is_control_name(&entry.name) && self.find(&entry.name) == Some(idx) {
+                operations.push(PatchEntry { name: entry.name.clone(), action: PatchAction::Replace, entry: Some(idx) });
+            }
+        }
+        Ok(operations)
+    }
+}
+
+/// Extraction through a shared reference, for archives in memory. An `IRO<MmapStorage>` is `Sync` and can be
+/// shared between threads via `&IRO` or `Arc<IRO>`.
+impl<S: SharedStorage> IRO<S> {
+    pub fn extract_to_shared<W: Write>(&self, writer: W, entry_idx: usize) -> IroResult<()> {
+        let entry = self.entry(entry_idx)?;
+        let reader = &self.storage.bytes()[entry.offset as usize..(entry.offset + entry.length) as usize];
+        Self::decode(reader, writer, entry)
+    }
+
+    /// Like [`IRO::extract_to_shared`], counting the stored bytes read into `read` as they are decompressed
+    pub(crate) fn extract_shared_counting<W: Write>(&self, writer: W, entry_idx: usize, read: &Cell<u64>) -> IroResult<()> {
+        let entry = self.entry(entry_idx)?;
+        let reader = &self.storage.bytes()[entry.offset as usize..(entry.offset + entry.length) as usize];
+        Self::decode(CountingReader { inner: reader, count: read }, writer, entry)
     }
 }
@@ -0,0 +1,198 @@
+use crate::{
+    imports::*,
+    iro::{name_extension, Compression, CountingWriter},
+};
+use byteorder::WriteBytesExt;
+use lzma_rust2::{LzmaOptions, LzmaWriter};
+
+/// How many leading bytes of an entry a policy gets to look at
+pub const HEAD_SIZE: usize = 256;
+
+pub const DEFAULT_LZMA_PRESET: u32 = 6;
+
+/// What a [`CompressionPolicy`] knows about an entry before it is written
+pub struct EntryInfo<'a> {
+    pub name: &'a str,
+    pub size: u64,
+    /// The first [`HEAD_SIZE`] bytes of the entry, or all of it if it is shorter
+    pub head: &'a [u8],
+}
+
+impl EntryInfo<'_> {
+    /// See [`name_extension`]
+    pub fn extension(&self) -> Option<&str> { name_extension(self.name) }
+}
+
+pub trait CompressionPolicy {
+    fn choose(&self, entry: &EntryInfo) -> Compression;
+
+    /// Called after an entry has been compressed, returning `false` stores it uncompressed instead
+    fn accept(&self, _entry: &EntryInfo, _compressed_size: u64) -> bool { true }
+}
+
+impl<F: Fn(&EntryInfo) -> Compression> CompressionPolicy for F {
+    fn choose(&self, entry: &EntryInfo) -> Compression { self(entry) }
+}
+
+pub struct StoreAll;
+
+impl CompressionPolicy for StoreAll {
+    fn choose(&self, _entry: &EntryInfo) -> Compression { Compression::None }
+}
+
+pub struct LzmaAll;
+
+impl CompressionPolicy for LzmaAll {
+    fn choose(&self, _entry: &EntryInfo) -> Compression { Compression::LZMA }
+}
+
+/// LZMA everything except formats that are already compressed
+#[derive(Debug, Clone)]
+pub struct Rules {
+    /// Extensions (without the dot, compared case-insensitively) that are always stored
+    pub store_extensions:     Vec<String>,
+    /// Entries smaller than this are stored
+    pub min_size:             u64,
+    /// Compressed entries are only kept if they shrink by at least this many percent
+    pub min_savings_percent:  u32,
+    /// Store DDS textures that use block compression (DXTn/BCn)
+    pub store_compressed_dds: bool,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        let store_extensions = ["png", "jpg", "jpeg", "ogg", "mp3", "mp4", "avi", "webm", "zip", "7z", "iro"];
+        Self {
+            store_extensions:     store_extensions.iter().map(|ext| ext.to_string()).collect(),
+            min_size:             64,
+            min_savings_percent:  5,
+            store_compressed_dds: true,
+        }
+    }
+}
+
+impl CompressionPolicy for Rules {
+    fn choose(&self, entry: &EntryInfo) -> Compression {
+        let stored_extension = entry
+            .extension()
+            .map(|ext| self.store_extensions.iter().any(|stored| stored.eq_ignore_ascii_case(ext)))
+            .unwrap_or(false);
+
+        if entry.size < self.min_size || stored_extension || (self.store_compressed_dds && is_block_compressed_dds(entry.head)) {
+            Compression::None
+        } else {
+            Compression::LZMA
+        }
+    }
+
+    fn accept(&self, entry: &EntryInfo, compressed_size: u64) -> bool {
+        compressed_size * 100 <= entry.size * (100 - self.min_savings_percent.min(100) as u64)
+    }
+}
+
+pub fn is_block_compressed_dds(head: &[u8]) -> bool {
+    const DDPF_FOURCC: u32 = 0x4;
+    let read_u32 = |offset: usize| head.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+
+    if !head.starts_with(b"DDS ") || read_u32(80).map(|flags| flags & DDPF_FOURCC == 0).unwrap_or(true) {
+        return false;
+    }
+    match head.get(84..88) {
+        Some(b"DXT1") | Some(b"DXT2") | Some(b"DXT3") | Some(b"DXT4") | Some(b"DXT5") | Some(b"ATI1") | Some(b"ATI2")
+        | Some(b"BC4U") | Some(b"BC4S") | Some(b"BC5U") | Some(b"BC5S") => true,
+        // DXGI_FORMAT_BC1_TYPELESS..=BC5_SNORM and BC6H_TYPELESS..=BC7_UNORM_SRGB
+        Some(b"DX10") => read_u32(128).map(|format| (70..=84).contains(&format) || (94..=99).contains(&format)).unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Compresses `reader` to `writer` in the layout the readers expect: unpacked size, properties length, the 5
+/// properties bytes and the raw LZMA stream without end marker. Returns the compressed length. The header is written
+/// before the data is read, so the data has to be exactly `size` bytes.
+pub(crate) fn compress_lzma<R: Read, W: Write>(name: &str, mut reader: R, writer: W, size: u64, preset: u32) -> IroResult<u64> {
+    let mut options = LzmaOptions::with_preset(preset);
+    // no point in a dictionary larger than the entry, it only costs memory on both ends
+    options.dict_size = options.dict_size.min(std::cmp::max(size, 4096) as u32);
+
+    let mut out = CountingWriter::new(writer);
+    out.write_u32::<LE>(size as u32)?;
+    out.write_u32::<LE>(5)?;
+    out.write_all(&[options.get_props()])?;
+    out.write_u32::<LE>(options.dict_size)?;
+
+    let mut encoder = LzmaWriter::new_no_header(out, &options, false)?;
+    let copied = io::copy(&mut reader, &mut encoder)?;
+    let out = encoder.finish()?;
+    if copied != size {
+        let message = format!("read {} bytes of data instead of {}", copied, size);
+        return Err(IroError::Lzma { name: name.to_string(), message });
+    }
+
+    Ok(out.count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        iro::{Version, IRO},
+        iro_writer::IroWriter,
+    };
+
+    #[test]
+    fn compress_lzma_streams_to_the_writer() {
+        let data = b"the same words again and again and again and again".repeat(100);
+        let mut out = Vec::new();
+        let length = compress_lzma("a.txt", &data[..], &mut out, data.len() as u64, DEFAULT_LZMA_PRESET).unwrap();
+        assert_eq!(length, out.len() as u64);
+        assert!(length < data.len() as u64 / 10);
+        assert_eq!(&out[..8], [&(data.len() as u32).to_le_bytes()[..], &5u32.to_le_bytes()].concat());
+    }
+
+    #[test]
+    fn compress_lzma_rejects_data_of_the_wrong_size() {
+        for size in [99, 101] {
+            match compress_lzma("a.bin", &[0u8; 100][..], io::sink(), size, DEFAULT_LZMA_PRESET) {
+                Err(IroError::Lzma { name, .. }) => assert_eq!(name, "a.bin"),
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    struct Reject;
+
+    impl CompressionPolicy for Reject {
+        fn choose(&self, _entry: &EntryInfo) -> Compression { Compression::LZMA }
+
+        fn accept(&self, _entry: &EntryInfo, _compressed_size: u64) -> bool { false }
+    }
+
+    #[test]
+    fn rejected_compression_is_stored() {
+        // random data comes out of LZMA a little larger, what's left of it follows the archive
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let noise: Vec<u8> = (0..10_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+            .collect();
+        let mut writer = IroWriter::new(Version::V2);
+        writer.set_compression_policy(Reject);
+        writer.add_bytes("a.txt", b"text text text text text text text text".to_vec());
+        writer.add_bytes("noise.bin", noise.clone());
+        let mut archive = io::Cursor::new(Vec::new());
+        writer.write(&mut archive).unwrap();
+        let end = archive.position() as usize;
+        let archive = archive.into_inner();
+        assert!(archive.len() > end);
+
+        let mut iro = IRO::from_slice(&archive[..end]).unwrap();
+        assert!(iro.files.iter().all(|entry| entry.compression == Compression::None));
+        let mut data = Vec::new();
+        iro.extract_by_name("noise.bin", &mut data).unwrap();
+        assert_eq!(data, noise);
+    }
+}
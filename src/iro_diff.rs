@@ -0,0 +1,157 @@
+use crate::{
+    imports::*,
+    iro::{decode_error, Compression, Entry, IRO},
+    storage::Storage,
+};
+
+/// An entry present in both archives whose contents differ
+#[derive(Debug, Clone)]
+pub struct Modified {
+    pub name:     String,
+    /// Index into the old archive's `files`
+    pub old:      usize,
+    /// Index into the new archive's `files`
+    pub new:      usize,
+    /// Uncompressed sizes, `None` if the entry's compression is unknown or its data can't be read
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+}
+
+/// Result of [`diff`]. Entries are matched by normalized name, duplicate names are compared by their last entry
+/// the same way [`IRO::find`] resolves them.
+#[derive(Debug, Clone, Default)]
+pub struct Diff {
+    /// Indices into the new archive's `files` of entries the old one doesn't have
+    pub added:     Vec<usize>,
+    /// Indices into the old archive's `files` of entries the new one doesn't have
+    pub removed:   Vec<usize>,
+    pub modified:  Vec<Modified>,
+    pub unchanged: usize,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool { self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty() }
+}
+
+/// Whether two readers yield the same bytes, compared chunk by chunk as they are read
+fn same_bytes<A, B, E, F>(mut old: A, mut new: B, old_error: E, new_error: F) -> IroResult<bool>
+where
+    A: BufRead,
+    B: BufRead,
+    E: Fn(io::Error) -> IroError,
+    F: Fn(io::Error) -> IroError,
+{
+    loop {
+        let (old_buf, new_buf) = (old.fill_buf().map_err(&old_error)?, new.fill_buf().map_err(&new_error)?);
+        let len = old_buf.len().min(new_buf.len());
+        if len == 0 {
+            return Ok(old_buf.is_empty() && new_buf.is_empty());
+        }
+        if old_buf[..len] != new_buf[..len] {
+            return Ok(false);
+        }
+        old.consume(len);
+        new.consume(len);
+    }
+}
+
+/// Whether the entries hold the same data. Entries stored identically are compared without decompressing them,
+/// otherwise the uncompressed sizes and then the uncompressed data are compared. Entries in an unknown compression
+/// can't be decompressed, they are the same only if their flags and stored bytes are.
+fn same_contents<A: Storage, B: Storage>(old: &mut IRO<A>, old_idx: usize, new: &mut IRO<B>, new_idx: usize) -> IroResult<bool> {
+    let (old_entry, new_entry) = (old.files[old_idx].clone(), new.files[new_idx].clone());
+    let unknown = |entry: &Entry| matches!(entry.compression, Compression::Unknown(_));
+    let undecodable = unknown(&old_entry) || unknown(&new_entry);
+    let same_storage =
+        if undecodable { old_entry.flags == new_entry.flags } else { old_entry.compression == new_entry.compression };
+    if same_storage
+        && old_entry.length == new_entry.length
+        && same_bytes(old.raw_reader(old_idx)?, new.raw_reader(new_idx)?, IroError::Io, IroError::Io)?
+    {
+        return Ok(true);
+    }
+    if undecodable {
+        return Ok(false);
+    }
+
+    let (old_reader, new_reader) = (old.open_entry(old_idx)?, new.open_entry(new_idx)?);
+    if old_reader.len() != new_reader.len() {
+        return Ok(false);
+    }
+    same_bytes(
+        io::BufReader::new(old_reader),
+        io::BufReader::new(new_reader),
+        |err| decode_error(&old_entry, err),
+        |err| decode_error(&new_entry, err),
+    )
+}
+
+/// Compares two archives entry by entry. Removed and modified entries are listed in the old archive's order, added
+/// entries in the new archive's.
+pub fn diff<A: Storage, B: Storage>(old: &mut IRO<A>, new: &mut IRO<B>) -> IroResult<Diff> {
+    let mut diff = Diff::default();
+
+    for old_idx in 0..old.files.len() {
+        let name = old.files[old_idx].name.clone();
+        // an earlier entry of a duplicated name is shadowed and not compared
+        if old.find(&name) != Some(old_idx) {
+            continue;
+        }
+        match new.find(&name) {
+            None => diff.removed.push(old_idx),
+            Some(new_idx) if same_contents(old, old_idx, new, new_idx)? => diff.unchanged += 1,
+            Some(new_idx) => diff.modified.push(Modified {
+                name,
+                old: old_idx,
+                new: new_idx,
+                old_size: old.unpacked_size(old_idx).ok(),
+                new_size: new.unpacked_size(new_idx).ok(),
+            }),
+        }
+    }
+
+    for (new_idx, entry) in new.files.iter().enumerate() {
+        if new.find(&entry.name) == Some(new_idx) && !old.contains(&entry.name) {
+            diff.added.push(new_idx);
+        }
+    }
+
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        iro::{ArchiveKind, Version},
+        iro_writer::raw_archive,
+    };
+
+    fn diff_raw(old: &[(&str, u32, &[u8])], new: &[(&str, u32, &[u8])]) -> Diff {
+        let (old, new) = (raw_archive(Version::V2, ArchiveKind::Full, old), raw_archive(Version::V2, ArchiveKind::Full, new));
+        diff(&mut IRO::from_slice(&old).unwrap(), &mut IRO::from_slice(&new).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn stored_entries() {
+        let diff = diff_raw(
+            &[("same.txt", 0, b"same"), ("changed.txt", 0, b"old"), ("gone.txt", 0, b"x")],
+            &[("Changed.txt", 0, b"new"), ("added.txt", 0, b"y"), ("SAME.TXT", 0, b"same")],
+        );
+        assert_eq!((diff.added, diff.removed, diff.unchanged), (vec![1], vec![2], 1));
+        assert_eq!(diff.modified.len(), 1);
+        let modified = &diff.modified[0];
+        assert_eq!((modified.old, modified.new, modified.old_size, modified.new_size), (1, 0, Some(3), Some(3)));
+    }
+
+    #[test]
+    fn unknown_compression_is_compared_as_stored() {
+        let diff = diff_raw(
+            &[("a.bin", 0x5, b"opaque"), ("b.bin", 0x5, b"opaque"), ("c.bin", 0x5, b"opaque"), ("d.bin", 0, b"plain")],
+            &[("a.bin", 0x5, b"opaque"), ("b.bin", 0x5, b"OPAQUE"), ("c.bin", 0x105, b"opaque"), ("d.bin", 0x5, b"plain")],
+        );
+        assert_eq!(diff.unchanged, 1);
+        let modified: Vec<_> = diff.modified.iter().map(|m| (m.name.as_str(), m.old_size, m.new_size)).collect();
+        assert_eq!(modified, [("b.bin", None, None), ("c.bin", None, None), ("d.bin", Some(5), None)]);
+    }
+}
@@ -0,0 +1,535 @@
+use crate::{
+    imports::*,
+    iro::{normalize_name, Entry, IRO},
+    storage::{SharedStorage, Storage},
+};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// What to do with entries whose names would be written outside the destination directory or can't be created on
+/// every platform, see [`check_name`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnsafeNamePolicy {
+    Skip,
+    /// Fail with [`IroError::UnsafeName`]
+    Error,
+    /// Extract to the path [`sanitize_name`] makes of the name
+    Rename,
+}
+
+pub type EntryFilter = dyn Fn(&Entry) -> bool + Send + Sync;
+
+/// Follows the progress of [`IRO::extract_all_into`] and [`IRO::extract_all_parallel`], and can cancel them.
+///
+/// With `extract_all_parallel` the methods are called from the worker threads, so entries can start and finish
+/// interleaved.
+pub trait ExtractObserver: Send + Sync {
+    /// Called once before anything is written with the number of selected entries and their stored size
+    fn started(&self, _entries: usize, _compressed_size: u64) {}
+
+    fn entry_started(&self, _entry_idx: usize, _entry: &Entry) {}
+
+    /// Called as data is written with the entry's stored bytes read and uncompressed bytes written so far. The
+    /// decoders read ahead, so the bytes read run a little ahead of the data written.
+    fn entry_progress(&self, _entry_idx: usize, _compressed_read: u64, _uncompressed_written: u64) {}
+
+    /// Called once the entry is on disk, the compressed size is the entry's `length`
+    fn entry_finished(&self, _entry_idx: usize, _entry: &Entry, _uncompressed_size: u64) {}
+
+    /// Called instead of `entry_started` for entries the [`UnsafeNamePolicy`] skips
+    fn entry_skipped(&self, _entry_idx: usize, _entry: &Entry) {}
+
+    /// Called instead of `entry_started` for selected entries [`IRO::extract_all_parallel`] leaves out because a
+    /// later selected entry has the same name
+    fn entry_shadowed(&self, _entry_idx: usize, _entry: &Entry) {}
+
+    /// Polled before each entry and while writing one. Returning `true` stops the extraction with
+    /// [`IroError::Cancelled`] and removes the partially written file.
+    fn is_cancelled(&self) -> bool { false }
+}
+
+struct NoObserver;
+
+impl ExtractObserver for NoObserver {}
+
+/// A flag another thread can set to cancel an extraction
+impl ExtractObserver for AtomicBool {
+    fn is_cancelled(&self) -> bool { self.load(Ordering::Relaxed) }
+}
+
+/// Settings for extracting several entries to disk
+#[derive(Clone)]
+pub struct ExtractOptions {
+    pub dest_dir:     path::PathBuf,
+    pub unsafe_names: UnsafeNamePolicy,
+    filter:           Option<Arc<EntryFilter>>,
+    observer:         Option<Arc<dyn ExtractObserver>>,
+}
+
+impl ExtractOptions {
+    pub fn new<P: Into<path::PathBuf>>(dest_dir: P) -> Self {
+        Self { dest_dir: dest_dir.into(), unsafe_names: UnsafeNamePolicy::Error, filter: None, observer: None }
+    }
+
+    /// Only extract entries `filter` returns `true` for, e.g. `move |entry| globs.matches(entry)` with a
+    /// [`GlobFilter`](crate::iro_glob::GlobFilter)
+    pub fn set_filter<F: Fn(&Entry) -> bool + Send + Sync + 'static>(&mut self, filter: F) {
+        self.filter = Some(Arc::new(filter));
+    }
+
+    pub fn selects(&self, entry: &Entry) -> bool { self.filter.as_ref().map(|filter| filter(entry)).unwrap_or(true) }
+
+    /// Reports progress to `observer`, keep a clone of the `Arc` to cancel from elsewhere
+    pub fn set_observer<O: ExtractObserver + 'static>(&mut self, observer: Arc<O>) { self.observer = Some(observer); }
+
+    fn observer(&self) -> &dyn ExtractObserver { self.observer.as_deref().unwrap_or(&NoObserver) }
+
+    fn start(&self, files: &[Entry]) -> IroResult<()> {
+        let observer = self.observer();
+        let selected = files.iter().filter(|entry| self.selects(entry));
+        let (entries, compressed_size) = selected.fold((0, 0), |(count, size), entry| (count + 1, size + entry.length));
+        observer.started(entries, compressed_size);
+        if observer.is_cancelled() { Err(IroError::Cancelled) } else { Ok(()) }
+    }
+
+    // extracts one entry with `extract`, which writes the entry's data to the writer it's given and counts the stored
+    // bytes it reads in the cell
+    fn extract_entry<F>(&self, entry_idx: usize, entry: &Entry, extract: F) -> IroResult<()>
+    where
+        F: FnOnce(&mut dyn Write, &Cell<u64>) -> IroResult<()>,
+    {
+        let observer = self.observer();
+        if !self.selects(entry) {
+            return Ok(());
+        }
+        if observer.is_cancelled() {
+            return Err(IroError::Cancelled);
+        }
+        let path = match output_path(&self.dest_dir, &entry.name, self.unsafe_names)? {
+            Some(path) => path,
+            None => {
+                observer.entry_skipped(entry_idx, entry);
+                return Ok(());
+            }
+        };
+
+        observer.entry_started(entry_idx, entry);
+        let read = Cell::new(0);
+        let mut writer = ProgressWriter { inner: create_file(&path)?, observer, entry_idx, read: &read, written: 0 };
+        let result = extract(&mut writer, &read).and_then(|()| Ok(writer.flush()?));
+        let written = writer.written;
+        drop(writer);
+
+        match result {
+            Err(_) if observer.is_cancelled() => {
+                let _ = fs::remove_file(&path);
+                Err(IroError::Cancelled)
+            }
+            Err(err) => Err(err),
+            Ok(()) => {
+                observer.entry_finished(entry_idx, entry, written);
+                Ok(())
+            }
+        }
+    }
+}
+
+struct ProgressWriter<'a, W> {
+    inner:     W,
+    observer:  &'a dyn ExtractObserver,
+    entry_idx: usize,
+    read:      &'a Cell<u64>,
+    written:   u64,
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.observer.is_cancelled() {
+            return Err(io::Error::other("extraction cancelled"));
+        }
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        self.observer.entry_progress(self.entry_idx, self.read.get(), self.written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+const RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9", "lpt1", "lpt2",
+    "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+const INVALID_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+// Windows reserves these names with any extension, `con.txt` can't be created either
+fn is_reserved(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or(component).trim_end();
+    RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+fn is_invalid_char(c: char) -> bool { c.is_control() || INVALID_CHARS.contains(&c) }
+
+/// Checks that `name` is a relative path that stays inside the directory it is extracted to and can be created on
+/// Windows as well, returning the reason if it isn't
+pub fn check_name(name: &str) -> Result<(), &'static str> {
+    if name.contains('\0') {
+        return Err("contains a NUL character");
+    }
+    if name.starts_with(['\\', '/']) {
+        return Err("absolute path");
+    }
+
+    let mut components = 0;
+    for component in name.split(['\\', '/']) {
+        match component {
+            "" | "." => continue,
+            ".." => return Err("refers to a parent directory"),
+            _ if component.contains(':') => return Err("contains a drive letter or colon"),
+            _ if component.chars().any(is_invalid_char) => return Err("contains a character Windows doesn't allow"),
+            _ if is_reserved(component) => return Err("contains a reserved Windows name"),
+            _ if component.ends_with(['.', ' ']) => return Err("contains a name ending in a dot or space"),
+            _ => components += 1,
+        }
+    }
+
+    if components == 0 { Err("empty name") } else { Ok(()) }
+}
+
+/// Rewrites `name` into a relative path that [`check_name`] accepts: parent and root references are dropped,
+/// invalid characters replaced by `_` and reserved names prefixed with `_`
+pub fn sanitize_name(name: &str) -> String {
+    let components: Vec<String> = name
+        .split(['\\', '/'])
+        .filter(|component| !matches!(*component, "" | "." | ".."))
+        .map(|component| {
+            let mut component: String = component.chars().map(|c| if is_invalid_char(c) { '_' } else { c }).collect();
+            component.truncate(component.trim_end_matches(['.', ' ']).len());
+            if component.is_empty() || is_reserved(&component) {
+                component.insert(0, '_');
+            }
+            component
+        })
+        .collect();
+
+    if components.is_empty() { "_".to_string() } else { components.join("\\") }
+}
+
+/// Where the entry `name` goes below `dest_dir`, or `None` if the policy skips it
+fn output_path(dest_dir: &path::Path, name: &str, policy: UnsafeNamePolicy) -> IroResult<Option<path::PathBuf>> {
+    let relative = match check_name(name) {
+        Ok(()) => name.to_string(),
+        Err(reason) => match policy {
+            UnsafeNamePolicy::Skip => return Ok(None),
+            UnsafeNamePolicy::Error => return Err(IroError::UnsafeName { name: name.to_string(), reason }),
+            UnsafeNamePolicy::Rename => sanitize_name(name),
+        },
+    };
+
+    let mut path = dest_dir.to_path_buf();
+    path.extend(relative.split(['\\', '/']).filter(|component| !matches!(*component, "" | ".")));
+    Ok(Some(path))
+}
+
+fn create_file(path: &path::Path) -> IroResult<io::BufWriter<fs::File>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(io::BufWriter::new(fs::File::create(path)?))
+}
+
+impl<S: Storage> IRO<S> {
+    /// Extracts the entry below `dest_dir`, returning the path it was written to or `None` if `policy` skipped it
+    pub fn extract_into<P: AsRef<path::Path>>(
+        &mut self, dest_dir: P, entry_idx: usize, policy: UnsafeNamePolicy,
+    ) -> IroResult<Option<path::PathBuf>> {
+        let name = &self.files.get(entry_idx).ok_or(IroError::NoSuchEntry(entry_idx))?.name;
+        let path = match output_path(dest_dir.as_ref(), name, policy)? {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let mut writer = create_file(&path)?;
+        self.extract_to(&mut writer, entry_idx)?;
+        writer.flush()?;
+        Ok(Some(path))
+    }
+
+    /// Extracts the entry relative to the current directory, failing if its name is unsafe
+    pub fn extract(&mut self, entry_idx: usize) -> IroResult<()> {
+        self.extract_into(".", entry_idx, UnsafeNamePolicy::Error).map(drop)
+    }
+
+    /// Extracts every entry `options` selects
+    pub fn extract_all_into(&mut self, options: &ExtractOptions) -> IroResult<()> {
+        options.start(&self.files)?;
+        for idx in 0..self.files.len() {
+            let entry = self.files[idx].clone();
+            options.extract_entry(idx, &entry, |writer, read| self.extract_counting(writer, idx, read))?;
+        }
+        Ok(())
+    }
+
+    pub fn extract_all(&mut self) -> IroResult<()> { self.extract_all_into(&ExtractOptions::new(".")) }
+}
+
+impl<S: SharedStorage> IRO<S> {
+    pub fn extract_shared_into<P: AsRef<path::Path>>(
+        &self, dest_dir: P, entry_idx: usize, policy: UnsafeNamePolicy,
+    ) -> IroResult<Option<path::PathBuf>> {
+        let name = &self.files.get(entry_idx).ok_or(IroError::NoSuchEntry(entry_idx))?.name;
+        let path = match output_path(dest_dir.as_ref(), name, policy)? {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let mut writer = create_file(&path)?;
+        self.extract_to_shared(&mut writer, entry_idx)?;
+        writer.flush()?;
+        Ok(Some(path))
+    }
+
+    pub fn extract_shared(&self, entry_idx: usize) -> IroResult<()> {
+        self.extract_shared_into(".", entry_idx, UnsafeNamePolicy::Error).map(drop)
+    }
+
+    /// Extracts every entry `options` selects on `workers` threads, or one per core if `workers` is 0. Stops at the
+    /// first error. Of selected entries whose names only differ in case or separators only the last is extracted,
+    /// the others are reported to [`ExtractObserver::entry_shadowed`].
+    pub fn extract_all_parallel(&self, options: &ExtractOptions, workers: usize) -> IroResult<()> {
+        options.start(&self.files)?;
+        // duplicates on different workers would write the same file at the same time
+        let selected: Vec<usize> = (0..self.files.len()).filter(|&idx| options.selects(&self.files[idx])).collect();
+        let last: HashMap<String, usize> =
+            selected.iter().map(|&idx| (normalize_name(&self.files[idx].name), idx)).collect();
+        let mut entries = Vec::with_capacity(selected.len());
+        for idx in selected {
+            let entry = &self.files[idx];
+            if last[&normalize_name(&entry.name)] == idx {
+                entries.push(idx);
+            } else {
+                options.observer().entry_shadowed(idx, entry);
+            }
+        }
+        let extract = |idx: usize| {
+            options.extract_entry(idx, &self.files[idx], |writer, read| self.extract_shared_counting(writer, idx, read))
+        };
+
+        let workers = match workers {
+            0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            n => n,
+        };
+        let workers = std::cmp::min(workers, entries.len());
+        if workers <= 1 {
+            return entries.iter().try_for_each(|&idx| extract(idx));
+        }
+
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let first_error = Mutex::new(None);
+        std::thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    while !failed.load(Ordering::Relaxed) {
+                        let idx = match entries.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            Some(&idx) => idx,
+                            None => break,
+                        };
+                        if let Err(err) = extract(idx) {
+                            failed.store(true, Ordering::Relaxed);
+                            first_error.lock().unwrap().get_or_insert(err);
+                        }
+                    }
+                });
+            }
+        });
+
+        match first_error.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    // name, reason check_name gives, what sanitize_name turns it into
+    const UNSAFE_NAMES: &[(&str, &str, &str)] = &[
+        ("..\\..\\x", "refers to a parent directory", "x"),
+        ("a/../../x", "refers to a parent directory", "a\\x"),
+        ("/abs", "absolute path", "abs"),
+        ("\\abs\\x", "absolute path", "abs\\x"),
+        ("C:\\x", "contains a drive letter or colon", "C_\\x"),
+        ("a:b", "contains a drive letter or colon", "a_b"),
+        ("a\0b", "contains a NUL character", "a_b"),
+        ("con.txt", "contains a reserved Windows name", "_con.txt"),
+        ("dir\\LPT1", "contains a reserved Windows name", "dir\\_LPT1"),
+        ("name.", "contains a name ending in a dot or space", "name"),
+        ("dir \\x", "contains a name ending in a dot or space", "dir\\x"),
+        ("", "empty name", "_"),
+        ("..", "refers to a parent directory", "_"),
+    ];
+
+    #[test]
+    fn check_name_rejects_unsafe_names() {
+        for &(name, reason, _) in UNSAFE_NAMES {
+            assert_eq!(check_name(name), Err(reason), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn check_name_accepts_safe_names() {
+        for name in ["a.txt", "field\\char\\aaaa.png", "battle/b.tex", ".\\x", "console.txt", "a..b"] {
+            assert_eq!(check_name(name), Ok(()), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn sanitize_name_makes_names_safe() {
+        for &(name, _, sanitized) in UNSAFE_NAMES {
+            assert_eq!(sanitize_name(name), sanitized, "{:?}", name);
+            assert_eq!(check_name(&sanitize_name(name)), Ok(()), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn output_path_follows_policy() {
+        let dest = Path::new("out");
+        for &(name, reason, sanitized) in UNSAFE_NAMES {
+            assert!(matches!(output_path(dest, name, UnsafeNamePolicy::Skip), Ok(None)), "{:?}", name);
+            match output_path(dest, name, UnsafeNamePolicy::Error) {
+                Err(IroError::UnsafeName { name: err_name, reason: err_reason }) => {
+                    assert_eq!((err_name.as_str(), err_reason), (name, reason))
+                }
+                other => panic!("{:?}: {:?}", name, other),
+            }
+            let expected: PathBuf = std::iter::once("out").chain(sanitized.split('\\')).collect();
+            assert_eq!(output_path(dest, name, UnsafeNamePolicy::Rename).unwrap(), Some(expected), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn output_path_keeps_safe_names_under_every_policy() {
+        for policy in [UnsafeNamePolicy::Skip, UnsafeNamePolicy::Error, UnsafeNamePolicy::Rename] {
+            let path = output_path(Path::new("out"), "field/char\\.\\a.png", policy).unwrap();
+            assert_eq!(path, Some(["out", "field", "char", "a.png"].iter().collect()));
+        }
+    }
+
+    struct Shadowed(Mutex<Vec<usize>>);
+
+    impl ExtractObserver for Shadowed {
+        fn entry_shadowed(&self, entry_idx: usize, _entry: &Entry) { self.0.lock().unwrap().push(entry_idx); }
+    }
+
+    fn extract_parallel(archive: &[u8], test: &str, filter: Option<&'static str>) -> (path::PathBuf, Vec<usize>) {
+        let dest = std::env::temp_dir().join(format!("moteria-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dest);
+        let mut options = ExtractOptions::new(&dest);
+        if let Some(name) = filter {
+            options.set_filter(move |entry| entry.name == name);
+        }
+        let observer = Arc::new(Shadowed(Mutex::new(Vec::new())));
+        options.set_observer(observer.clone());
+        IRO::from_slice(archive).unwrap().extract_all_parallel(&options, 4).unwrap();
+        let shadowed = observer.0.lock().unwrap().clone();
+        (dest, shadowed)
+    }
+
+    fn files_below(dir: &Path) -> Vec<(String, String)> {
+        let mut files = Vec::new();
+        for dir_entry in fs::read_dir(dir).unwrap() {
+            let path = dir_entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(files_below(&path));
+            } else {
+                files.push((path.to_string_lossy().into_owned(), fs::read_to_string(&path).unwrap()));
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn parallel_extraction_writes_the_last_selected_duplicate() {
+        use crate::{
+            iro::{ArchiveKind, Version},
+            iro_writer::raw_archive,
+        };
+        let archive = raw_archive(Version::V2, ArchiveKind::Full, &[
+            ("x\\keep.txt", 0, b"first"),
+            ("other.txt", 0, b"other"),
+            ("X\\KEEP.TXT", 0, b"second"),
+        ]);
+
+        let (dest, shadowed) = extract_parallel(&archive, "all", None);
+        let contents: Vec<String> = files_below(&dest).into_iter().map(|(_, contents)| contents).collect();
+        assert_eq!(contents.len(), 2);
+        assert!(contents.contains(&"second".to_string()) && !contents.contains(&"first".to_string()));
+        assert_eq!(shadowed, [0]);
+        fs::remove_dir_all(&dest).unwrap();
+
+        // the filter picks the shadowed entry, it is the only one selected and has to be extracted
+        let (dest, shadowed) = extract_parallel(&archive, "filtered", Some("x\\keep.txt"));
+        assert_eq!(fs::read_to_string(dest.join("x").join("keep.txt")).unwrap(), "first");
+        assert_eq!(files_below(&dest).len(), 1);
+        assert!(shadowed.is_empty());
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[derive(Default)]
+    struct Progress(Mutex<HashMap<usize, Vec<(u64, u64)>>>);
+
+    impl ExtractObserver for Progress {
+        fn entry_progress(&self, entry_idx: usize, compressed_read: u64, uncompressed_written: u64) {
+            self.0.lock().unwrap().entry(entry_idx).or_default().push((compressed_read, uncompressed_written));
+        }
+    }
+
+    #[test]
+    fn progress_reports_bytes_read_and_written() {
+        use crate::{
+            iro::{Compression, Version},
+            iro_compress::EntryInfo,
+            iro_writer::IroWriter,
+        };
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 7) as u8).collect();
+        let mut writer = IroWriter::new(Version::V2);
+        writer.set_compression_policy(|entry: &EntryInfo| {
+            if entry.name.ends_with(".lzma") { Compression::LZMA } else { Compression::None }
+        });
+        writer.add_bytes("stored.bin", data.clone());
+        writer.add_bytes("packed.lzma", data.clone());
+        let mut archive = io::Cursor::new(Vec::new());
+        writer.write(&mut archive).unwrap();
+        let archive = archive.into_inner();
+        let iro = IRO::from_slice(&archive).unwrap();
+
+        let dest = std::env::temp_dir().join(format!("moteria-progress-{}", std::process::id()));
+        let mut options = ExtractOptions::new(&dest);
+        let observer = Arc::new(Progress::default());
+        options.set_observer(observer.clone());
+        iro.extract_all_parallel(&options, 1).unwrap();
+        fs::remove_dir_all(&dest).unwrap();
+
+        let progress = observer.0.lock().unwrap();
+        let stored = &progress[&0];
+        assert!(stored.iter().all(|&(read, written)| read == written));
+        assert_eq!(stored.last(), Some(&(data.len() as u64, data.len() as u64)));
+
+        let packed = &progress[&1];
+        let length = iro.files[1].length;
+        assert!(length < data.len() as u64 / 10);
+        assert!(packed.windows(2).all(|pair| pair[0].0 <= pair[1].0 && pair[0].1 < pair[1].1));
+        assert!(packed.iter().all(|&(read, _)| read > 0 && read <= length));
+        assert_eq!(packed.last().unwrap().1, data.len() as u64);
+    }
+}
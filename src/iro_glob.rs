@@ -0,0 +1,153 @@
+use crate::iro::{normalize_name, Entry};
+
+/// A glob over entry names, matched the way 7th Heaven compares names: case-insensitively with either separator.
+///
+/// `*` and `?` match within a folder, `**` also matches across folders and `**\` matches no folder at all, so
+/// `battle\*.png` only matches files directly in `battle` while `**\*.png` matches every PNG.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    /// `?`, any character but a separator
+    AnyChar,
+    /// `*`, anything within a folder
+    Star,
+    /// `**`, anything at all
+    GlobStar,
+    /// `**\`, nothing or anything ending in a separator
+    Folders,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Self {
+        let mut tokens = Vec::new();
+        let pattern = normalize_name(pattern);
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            tokens.push(match c {
+                '*' if chars.next_if_eq(&'*').is_some() => {
+                    if chars.next_if_eq(&'\\').is_some() { Token::Folders } else { Token::GlobStar }
+                }
+                '*' => Token::Star,
+                '?' => Token::AnyChar,
+                c => Token::Char(c),
+            });
+        }
+        Self { tokens }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let name: Vec<char> = normalize_name(name).chars().collect();
+        glob_match(&self.tokens, &name)
+    }
+}
+
+/// Matches token by token, tracking every position in `name` the pattern so far can end at. Takes time proportional
+/// to the pattern length times the name length however many stars there are.
+fn glob_match(tokens: &[Token], name: &[char]) -> bool {
+    let mut ends = vec![false; name.len() + 1];
+    ends[0] = true;
+    let mut next = vec![false; name.len() + 1];
+
+    for token in tokens {
+        // whether a star could have started at or before the current position
+        let mut open = false;
+        for pos in 0..=name.len() {
+            next[pos] = match token {
+                Token::Char(c) => pos > 0 && ends[pos - 1] && name[pos - 1] == *c,
+                Token::AnyChar => pos > 0 && ends[pos - 1] && name[pos - 1] != '\\',
+                Token::Star => {
+                    open = ends[pos] || (open && name[pos - 1] != '\\');
+                    open
+                }
+                Token::GlobStar => {
+                    open |= ends[pos];
+                    open
+                }
+                Token::Folders => {
+                    let matched = ends[pos] || (open && name[pos - 1] == '\\');
+                    open |= ends[pos];
+                    matched
+                }
+            };
+        }
+        std::mem::swap(&mut ends, &mut next);
+        if !ends.contains(&true) {
+            return false;
+        }
+    }
+    ends[name.len()]
+}
+
+/// Selects entries matching any of `include`, or all entries if it is empty, unless they match one of `exclude`
+#[derive(Debug, Clone, Default)]
+pub struct GlobFilter {
+    pub include: Vec<Glob>,
+    pub exclude: Vec<Glob>,
+}
+
+impl GlobFilter {
+    pub fn matches(&self, entry: &Entry) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|glob| glob.matches(&entry.name)))
+            && !self.exclude.iter().any(|glob| glob.matches(&entry.name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // pattern, name, whether it matches
+    const GLOBS: &[(&str, &str, bool)] = &[
+        ("battle\\b.tex", "battle\\b.tex", true),
+        ("battle\\b.tex", "battle\\b.texx", false),
+        ("BATTLE/B.TEX", "battle\\b.tex", true),
+        ("battle\\b.tex", "Battle/B.Tex", true),
+        ("b?.tex", "b1.tex", true),
+        ("b?.tex", "b.tex", false),
+        ("a?b", "a\\b", false),
+        ("*.png", "a.png", true),
+        ("*.png", ".png", true),
+        ("*.png", "field\\a.png", false),
+        ("battle\\*", "battle\\b.tex", true),
+        ("battle\\*", "battle\\sub\\c.dds", false),
+        ("*\\*.dds", "battle\\c.dds", true),
+        ("*\\*.dds", "battle\\sub\\c.dds", false),
+        ("**", "battle\\sub\\c.dds", true),
+        ("**", "", true),
+        ("battle\\**", "battle\\sub\\c.dds", true),
+        ("battle**.dds", "battle\\sub\\c.dds", true),
+        ("**.png", "field\\char\\a.png", true),
+        ("**\\*.png", "a.png", true),
+        ("**\\*.png", "field\\char\\a.png", true),
+        ("**\\*.png", "field\\char\\a.dds", false),
+        ("battle\\**\\c.dds", "battle\\c.dds", true),
+        ("battle\\**\\c.dds", "battle\\sub\\deeper\\c.dds", true),
+        ("battle\\**\\c.dds", "battlefield\\c.dds", false),
+        ("battle\\**\\c.dds", "battle\\subc.dds", false),
+        ("**/*.PNG", "Field/Char/A.png", true),
+        ("", "", true),
+        ("", "a", false),
+        ("a*b*c", "aXbYc", true),
+        ("a*b*c", "aXbY\\c", false),
+    ];
+
+    #[test]
+    fn glob_semantics() {
+        for &(pattern, name, expected) in GLOBS {
+            assert_eq!(Glob::new(pattern).matches(name), expected, "{:?} on {:?}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn many_stars_are_not_exponential() {
+        let name = "a".repeat(32 * 1024);
+        assert!(!Glob::new("**a**a**a**a**a**a**a**b").matches(&name));
+        assert!(!Glob::new("*a*a*a*a*a*a*a*b").matches(&name));
+        assert!(Glob::new("**a**a**a**a**a**a**a**").matches(&name));
+    }
+}
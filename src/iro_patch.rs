@@ -0,0 +1,346 @@
+use crate::{
+    imports::*,
+    iro::{normalize_name, ArchiveKind, PatchAction, Version, IRO, PATCH_DELETED_ENTRY},
+    iro_diff::diff,
+    iro_writer,
+    storage::Storage,
+};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone)]
+enum Source {
+    /// An entry of the base or old archive
+    Old(usize),
+    /// An entry of the patch or new archive
+    New(usize),
+    /// Data made up while patching
+    Bytes(Vec<u8>),
+}
+
+struct Planned {
+    name:   String,
+    source: Source,
+    flags:  u32,
+    length: u64,
+}
+
+/// Writes `base` with the operations of `patch` applied to `writer`.
+///
+/// Replaced entries keep their position in the base directory, added entries are appended in patch order and
+/// removed entries are dropped. The patch's control entries like [`PATCH_DELETED_ENTRY`] aren't written and of
+/// duplicated base names only the entry [`IRO::find`] resolves to is kept. Entry data is copied as stored, nothing
+/// is decompressed. A V0 base that outgrows 32-bit offsets is written as V2.
+pub fn apply_patch<B: Storage, P: Storage, W: Write>(base: &mut IRO<B>, patch: &mut IRO<P>, writer: W) -> IroResult<()> {
+    if patch.kind != ArchiveKind::Patch {
+        return Err(IroError::NotAPatch);
+    }
+
+    let operations = patch.patch_entries()?;
+    let mut replacements = HashMap::new();
+    let mut removals = HashSet::new();
+    for operation in operations.iter() {
+        let name = normalize_name(&operation.name);
+        match (operation.action, operation.entry) {
+            (PatchAction::Replace, Some(idx)) => {
+                replacements.insert(name, idx);
+            }
+            _ => {
+                removals.insert(name);
+            }
+        }
+    }
+
+    let mut planned = Vec::with_capacity(base.files.len() + replacements.len());
+    let mut used = HashSet::new();
+    for (idx, entry) in base.files.iter().enumerate() {
+        // a shadowed duplicate would otherwise be written, or replaced, once more
+        if base.find(&entry.name) != Some(idx) {
+            continue;
+        }
+        let name = normalize_name(&entry.name);
+        if let Some(&patch_idx) = replacements.get(&name) {
+            let patch_entry = &patch.files[patch_idx];
+            planned.push(Planned {
+                name:   patch_entry.name.clone(),
+                source: Source::New(patch_idx),
+                flags:  patch_entry.flags.into(),
+                length: patch_entry.length,
+            });
+            used.insert(patch_idx);
+        } else if !removals.contains(&name) {
+            planned.push(Planned {
+                name:   entry.name.clone(),
+                source: Source::Old(idx),
+                flags:  entry.flags.into(),
+                length: entry.length,
+            });
+        }
+    }
+    for operation in operations.iter() {
+        match operation.entry {
+            Some(idx) if !used.contains(&idx) => {
+                let patch_entry = &patch.files[idx];
+                planned.push(Planned {
+                    name:   patch_entry.name.clone(),
+                    source: Source::New(idx),
+                    flags:  patch_entry.flags.into(),
+                    length: patch_entry.length,
+                });
+            }
+            _ => (),
+        }
+    }
+
+    write_planned(base, patch, writer, ArchiveKind::Full, base.version, &planned)
+}
+
+/// Writes a patch archive that turns `old` into `new` when applied with [`apply_patch`].
+///
+/// The patch holds the entries of `new` that are missing from `old` or differ from it, in `new`'s order, followed by
+/// a [`PATCH_DELETED_ENTRY`] listing the entries `new` no longer has. Entry data is copied as stored.
+pub fn make_patch<O: Storage, N: Storage, W: Write>(old: &mut IRO<O>, new: &mut IRO<N>, writer: W) -> IroResult<()> {
+    let diff = diff(old, new)?;
+
+    let mut changed: Vec<usize> = diff.modified.iter().map(|modified| modified.new).chain(diff.added).collect();
+    changed.sort_unstable();
+    let mut planned: Vec<Planned> = changed
+        .into_iter()
+        .map(|idx| {
+            let entry = &new.files[idx];
+            Planned {
+                name:   entry.name.clone(),
+                source: Source::New(idx),
+                flags:  entry.flags.into(),
+                length: entry.length,
+            }
+        })
+        .collect();
+    if !diff.removed.is_empty() {
+        let names: Vec<&str> = diff.removed.iter().map(|&idx| old.files[idx].name.as_str()).collect();
+        let data: Vec<u8> = names.join("\n").encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+        planned.push(Planned {
+            name:   PATCH_DELETED_ENTRY.to_string(),
+            length: data.len() as u64,
+            source: Source::Bytes(data),
+            flags:  0,
+        });
+    }
+
+    write_planned(old, new, writer, ArchiveKind::Patch, new.version, &planned)
+}
+
+/// Writes the planned entries, switching a V0 archive to V2 if its data outgrows 32-bit offsets
+fn write_planned<O: Storage, N: Storage, W: Write>(
+    old: &mut IRO<O>, new: &mut IRO<N>, writer: W, kind: ArchiveKind, version: Version, planned: &[Planned],
+) -> IroResult<()> {
+    let mut version = version;
+    let mut data_start = directory_end(planned, version)?;
+    let data_length: u64 = planned.iter().map(|p| p.length).sum();
+    if !version.has_wide_offsets() && data_start + data_length > u32::MAX as u64 {
+        version = Version::V2;
+        data_start = directory_end(planned, version)?;
+    }
+
+    let mut writer = io::BufWriter::new(writer);
+    iro_writer::write_header(&mut writer, version, kind, planned.len())?;
+    let mut offset = data_start;
+    for p in planned.iter() {
+        iro_writer::write_record(&mut writer, version, &p.name, p.flags, offset, p.length)?;
+        offset += p.length;
+    }
+    for p in planned.iter() {
+        match &p.source {
+            Source::Old(idx) => old.copy_raw_to(&mut writer, *idx)?,
+            Source::New(idx) => new.copy_raw_to(&mut writer, *idx)?,
+            Source::Bytes(data) => writer.write_all(data)?,
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn directory_end(planned: &[Planned], version: Version) -> IroResult<u64> {
+    let mut end = iro_writer::HEADER_SIZE;
+    for p in planned {
+        end += iro_writer::record_size(&p.name, version).ok_or_else(|| IroError::NameTooLong(p.name.clone()))? as u64;
+    }
+    Ok(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iro_writer::raw_archive;
+
+    fn patch_between(old: &[u8], new: &[u8]) -> Vec<u8> {
+        let mut patch = Vec::new();
+        make_patch(&mut IRO::from_slice(old).unwrap(), &mut IRO::from_slice(new).unwrap(), &mut patch).unwrap();
+        patch
+    }
+
+    fn apply(base: &[u8], patch: &[u8]) -> Vec<u8> {
+        let mut applied = Vec::new();
+        apply_patch(&mut IRO::from_slice(base).unwrap(), &mut IRO::from_slice(patch).unwrap(), &mut applied).unwrap();
+        applied
+    }
+
+    fn assert_same(a: &[u8], b: &[u8]) {
+        let diff = diff(&mut IRO::from_slice(a).unwrap(), &mut IRO::from_slice(b).unwrap()).unwrap();
+        assert!(diff.is_empty(), "{:?}", diff);
+    }
+
+    #[test]
+    fn changed_entry_in_unknown_compression() {
+        let old = raw_archive(Version::V2, ArchiveKind::Full, &[
+            ("opaque.bin", 0x5, b"old data"),
+            ("keep.txt", 0, b"keep"),
+            ("gone.txt", 0, b"gone"),
+        ]);
+        let new = raw_archive(Version::V2, ArchiveKind::Full, &[
+            ("opaque.bin", 0x5, b"new data!"),
+            ("keep.txt", 0, b"keep"),
+            ("added.bin", 0x1_0005, b"more"),
+        ]);
+
+        let patch = patch_between(&old, &new);
+        let patch_iro = IRO::from_slice(&patch).unwrap();
+        assert_eq!(patch_iro.kind, ArchiveKind::Patch);
+        let names: Vec<&str> = patch_iro.files.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["opaque.bin", "added.bin", PATCH_DELETED_ENTRY]);
+        assert_eq!((patch_iro.files[0].flags.0, patch_iro.files[1].flags.0), (0x5, 0x1_0005));
+
+        assert_same(&apply(&old, &patch), &new);
+    }
+
+    fn write(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = iro_writer::IroWriter::new(Version::V2);
+        for (name, data) in entries {
+            writer.add_bytes(*name, data.to_vec());
+        }
+        let mut out = io::Cursor::new(Vec::new());
+        writer.write(&mut out).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn make_and_apply() {
+        let old = write(&[("a.txt", b"a"), ("dir\\b.txt", b"b"), ("gone.txt", b"gone"), ("same.txt", b"same")]);
+        let new = write(&[("DIR/B.TXT", b"changed b"), ("a.txt", b"a"), ("same.txt", b"same"), ("added.txt", b"new")]);
+        let patch = patch_between(&old, &new);
+        let names: Vec<String> = IRO::from_slice(&patch).unwrap().files.into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["DIR/B.TXT", "added.txt", PATCH_DELETED_ENTRY]);
+
+        let applied = apply(&old, &patch);
+        assert_same(&applied, &new);
+        // the replaced entry keeps its place in the base, additions go last
+        let names: Vec<String> = IRO::from_slice(&applied).unwrap().files.into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["a.txt", "DIR/B.TXT", "same.txt", "added.txt"]);
+
+        // applying a patch between identical archives changes nothing
+        assert_same(&apply(&old, &patch_between(&old, &old)), &old);
+    }
+
+    #[test]
+    fn removal_and_replacement_of_the_same_name() {
+        let base = write(&[("a.txt", b"old a"), ("b.txt", b"b")]);
+        let deleted: Vec<u8> = "A.TXT\nb.txt\n".encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+        let patch = raw_archive(Version::V2, ArchiveKind::Patch, &[
+            (PATCH_DELETED_ENTRY, 0, &deleted),
+            ("a.txt", 0, b"new a"),
+        ]);
+
+        let applied = apply(&base, &patch);
+        let mut iro = IRO::from_slice(&applied).unwrap();
+        let names: Vec<&str> = iro.files.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["a.txt"]);
+        let mut data = Vec::new();
+        iro.extract_to(&mut data, 0).unwrap();
+        assert_eq!(data, b"new a");
+    }
+
+    /// An archive whose entry data reads as zeroes without being held anywhere
+    struct Sparse {
+        directory: Vec<u8>,
+        size:      u64,
+    }
+
+    impl Storage for Sparse {
+        fn size(&self) -> u64 { self.size }
+
+        fn read_range(&mut self, offset: u64, length: u64) -> io::Result<Box<dyn BufRead + '_>> {
+            match self.directory.get(offset as usize..) {
+                Some(directory) if !directory.is_empty() => Ok(Box::new(&directory[..directory.len().min(length as usize)])),
+                _ => Ok(Box::new(Zeroes(length))),
+            }
+        }
+    }
+
+    struct Zeroes(u64);
+
+    impl Read for Zeroes {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.fill_buf()?.len().min(buf.len());
+            buf[..len].fill(0);
+            self.consume(len);
+            Ok(len)
+        }
+    }
+
+    impl BufRead for Zeroes {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            static ZEROES: [u8; 1 << 16] = [0; 1 << 16];
+            Ok(&ZEROES[..self.0.min(ZEROES.len() as u64) as usize])
+        }
+
+        fn consume(&mut self, amt: usize) { self.0 -= amt as u64; }
+    }
+
+    /// Keeps the start of what is written and counts the rest
+    struct Head {
+        head:    Vec<u8>,
+        written: u64,
+    }
+
+    impl Write for Head {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let keep = buf.len().min(4096usize.saturating_sub(self.head.len()));
+            self.head.extend_from_slice(&buf[..keep]);
+            self.written += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn v0_base_outgrowing_32_bit_offsets_is_written_as_v2() {
+        const HALF: u64 = 1 << 31;
+        let names = ["first.bin", "second.bin"];
+        let mut directory = Vec::new();
+        iro_writer::write_header(&mut directory, Version::V0, ArchiveKind::Full, names.len()).unwrap();
+        let data_start = iro_writer::HEADER_SIZE
+            + names.iter().map(|name| iro_writer::record_size(name, Version::V0).unwrap() as u64).sum::<u64>();
+        for (idx, name) in names.iter().enumerate() {
+            iro_writer::write_record(&mut directory, Version::V0, name, 0, data_start + idx as u64 * HALF, HALF).unwrap();
+        }
+        let mut base = IRO::from_storage(Sparse { directory, size: data_start + 2 * HALF }).unwrap();
+        assert_eq!(base.version, Version::V0);
+
+        let patch = raw_archive(Version::V2, ArchiveKind::Patch, &[("added.txt", 0, b"added")]);
+        let mut out = Head { head: Vec::new(), written: 0 };
+        apply_patch(&mut base, &mut IRO::from_slice(&patch).unwrap(), &mut out).unwrap();
+
+        let applied = IRO::from_slice(&out.head).unwrap();
+        assert_eq!(applied.version, Version::V2);
+        let data_start = applied.directory_range().end;
+        let offsets: Vec<(&str, u64, u64)> =
+            applied.files.iter().map(|entry| (entry.name.as_str(), entry.offset, entry.length)).collect();
+        assert_eq!(offsets, [
+            ("first.bin", data_start, HALF),
+            ("second.bin", data_start + HALF, HALF),
+            ("added.txt", data_start + 2 * HALF, 5),
+        ]);
+        assert_eq!(out.written, data_start + 2 * HALF + 5);
+    }
+}
@@ -0,0 +1,52 @@
+use crate::{
+    imports::*,
+    iro::{Compression, Version, IRO},
+    iro_compress::{CompressionPolicy, Rules, DEFAULT_LZMA_PRESET},
+    iro_writer::{write_archive, EntryData, EntrySource},
+    storage::Storage,
+};
+
+/// How [`IRO::repack`] writes the new archive. Entries are compressed according to [`Rules`] unless another policy
+/// is set.
+pub struct RepackOptions {
+    pub lzma_preset: u32,
+    /// Write V0 archives as V2, lifting the 4 GiB limit of 32-bit offsets
+    pub upgrade_v0:  bool,
+    policy:          Box<dyn CompressionPolicy>,
+}
+
+impl Default for RepackOptions {
+    fn default() -> Self { Self { lzma_preset: DEFAULT_LZMA_PRESET, upgrade_v0: false, policy: Box::new(Rules::default()) } }
+}
+
+impl RepackOptions {
+    pub fn set_compression_policy<P: CompressionPolicy + 'static>(&mut self, policy: P) { self.policy = Box::new(policy); }
+}
+
+impl<S: Storage> IRO<S> {
+    /// Writes every entry to a new archive in the same order and under the same names, recompressing them according
+    /// to `options`. Entries are decompressed one at a time, the archive is never held in memory as a whole.
+    ///
+    /// Entries with an unknown compression are copied as stored. The directory is written last, so `writer` has to
+    /// be seekable. Like [`IroWriter::write`](crate::iro_writer::IroWriter::write) it can leave unused bytes after the
+    /// archive, write to a new or truncated file.
+    pub fn repack<W: Write + io::Seek>(&mut self, writer: W, options: &RepackOptions) -> IroResult<()> {
+        let version = if options.upgrade_v0 && self.version == Version::V0 { Version::V2 } else { self.version };
+        write_archive(writer, version, self.kind, &*options.policy, options.lzma_preset, self)
+    }
+}
+
+impl<S: Storage> EntrySource for IRO<S> {
+    fn entry_count(&self) -> usize { self.files.len() }
+
+    fn entry_name(&self, idx: usize) -> &str { &self.files[idx].name }
+
+    fn open_data(&mut self, idx: usize) -> IroResult<EntryData<'_>> {
+        let flags = self.files[idx].flags;
+        if matches!(flags.compression(), Compression::Unknown(_)) {
+            return Ok(EntryData::Raw { flags, reader: self.raw_reader(idx)? });
+        }
+        let reader = self.open_entry(idx)?;
+        Ok(EntryData::Plain { flags, size: reader.len(), reader: Box::new(reader) })
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{
+    iro::{name_extension, IRO},
+    storage::Storage,
+};
+use std::collections::BTreeMap;
+
+/// Compressed size over uncompressed size, 1 for empty data
+fn ratio(compressed_size: u64, uncompressed_size: u64) -> f64 {
+    if uncompressed_size == 0 { 1.0 } else { compressed_size as f64 / uncompressed_size as f64 }
+}
+
+/// Ratio of a group's entries with a known uncompressed size, `None` if none of them has one
+fn known_ratio(known_compressed_size: u64, uncompressed_size: u64, count: usize, unknown_sizes: usize) -> Option<f64> {
+    if unknown_sizes == count && count > 0 { None } else { Some(ratio(known_compressed_size, uncompressed_size)) }
+}
+
+#[derive(Debug, Clone)]
+pub struct EntryStats {
+    /// Index into `IRO.files`
+    pub entry:             usize,
+    pub name:              String,
+    pub compressed_size:   u64,
+    /// `None` if the entry's compression is unknown or its data can't be read
+    pub uncompressed_size: Option<u64>,
+}
+
+impl EntryStats {
+    pub fn ratio(&self) -> Option<f64> { Some(ratio(self.compressed_size, self.uncompressed_size?)) }
+
+    /// Lowercased [`name_extension`] of the entry, empty if it has none
+    pub fn extension(&self) -> String { name_extension(&self.name).map(str::to_lowercase).unwrap_or_default() }
+}
+
+/// Totals of every entry sharing an extension
+#[derive(Debug, Clone, Default)]
+pub struct ExtensionStats {
+    pub extension:         String,
+    pub count:             usize,
+    pub compressed_size:   u64,
+    /// Total of the entries whose uncompressed size is known
+    pub uncompressed_size: u64,
+    /// Number of entries whose uncompressed size is unknown
+    pub unknown_sizes:     usize,
+    known_compressed_size: u64,
+}
+
+impl ExtensionStats {
+    /// Ratio of the entries whose uncompressed size is known
+    pub fn ratio(&self) -> Option<f64> {
+        known_ratio(self.known_compressed_size, self.uncompressed_size, self.count, self.unknown_sizes)
+    }
+}
+
+/// Result of [`IRO::stats`], one entry per file in directory order
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub entries:           Vec<EntryStats>,
+    pub compressed_size:   u64,
+    /// Total of the entries whose uncompressed size is known
+    pub uncompressed_size: u64,
+    /// Number of entries whose uncompressed size is unknown
+    pub unknown_sizes:     usize,
+    known_compressed_size: u64,
+}
+
+impl Stats {
+    /// Ratio of the entries whose uncompressed size is known
+    pub fn ratio(&self) -> Option<f64> {
+        known_ratio(self.known_compressed_size, self.uncompressed_size, self.entries.len(), self.unknown_sizes)
+    }
+
+    /// Totals per extension, largest compressed total first
+    pub fn by_extension(&self) -> Vec<ExtensionStats> {
+        let mut totals: BTreeMap<String, ExtensionStats> = BTreeMap::new();
+        for entry in self.entries.iter() {
+            let extension = entry.extension();
+            let total = totals
+                .entry(extension.clone())
+                .or_insert_with(|| ExtensionStats { extension, ..Default::default() });
+            total.count += 1;
+            total.compressed_size += entry.compressed_size;
+            match entry.uncompressed_size {
+                Some(size) => {
+                    total.known_compressed_size += entry.compressed_size;
+                    total.uncompressed_size += size;
+                }
+                None => total.unknown_sizes += 1,
+            }
+        }
+
+        let mut totals: Vec<ExtensionStats> = totals.into_values().collect();
+        totals.sort_by_key(|total| std::cmp::Reverse(total.compressed_size));
+        totals
+    }
+
+    /// The `count` entries taking up the most space in the archive, largest first
+    pub fn largest(&self, count: usize) -> Vec<&EntryStats> {
+        let mut entries: Vec<&EntryStats> = self.entries.iter().collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.compressed_size));
+        entries.truncate(count);
+        entries
+    }
+}
+
+impl<S: Storage> IRO<S> {
+    /// Collects the sizes of every entry. Uncompressed sizes of LZMA entries come from their header, LZS entries
+    /// have to be decompressed. Entries with an unknown compression or damaged data are reported without an
+    /// uncompressed size instead of failing the whole archive.
+    pub fn stats(&mut self) -> Stats {
+        let mut entries = Vec::with_capacity(self.files.len());
+        for idx in 0..self.files.len() {
+            let uncompressed_size = self.unpacked_size(idx).ok();
+            let entry = &self.files[idx];
+            entries.push(EntryStats {
+                entry: idx,
+                name: entry.name.clone(),
+                compressed_size: entry.length,
+                uncompressed_size,
+            });
+        }
+
+        let known = entries.iter().filter(|entry| entry.uncompressed_size.is_some());
+        Stats {
+            compressed_size: entries.iter().map(|entry| entry.compressed_size).sum(),
+            uncompressed_size: known.clone().filter_map(|entry| entry.uncompressed_size).sum(),
+            unknown_sizes: entries.iter().filter(|entry| entry.uncompressed_size.is_none()).count(),
+            known_compressed_size: known.map(|entry| entry.compressed_size).sum(),
+            entries,
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{iro::IRO, storage::Storage};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone)]
+pub struct TreeFile {
+    /// Last component of the entry name
+    pub name:              String,
+    /// Index into `IRO.files`
+    pub entry:             usize,
+    pub compressed_size:   u64,
+    /// `None` if the entry's compression is unknown or its data can't be read
+    pub uncompressed_size: Option<u64>,
+}
+
+/// A folder of a [`Tree`] with the totals of everything below it
+#[derive(Debug, Clone, Default)]
+pub struct Folder {
+    pub name:              String,
+    /// Full path with `\` separators, empty for the root
+    pub path:              String,
+    pub file_count:        usize,
+    pub compressed_size:   u64,
+    /// Total of the files whose uncompressed size is known
+    pub uncompressed_size: u64,
+    /// Number of files below this folder whose uncompressed size is unknown
+    pub unknown_sizes:     usize,
+    // keyed by the lowercased name, so children are listed sorted and looked up case-insensitively
+    folders:               BTreeMap<String, Folder>,
+    files:                 Vec<TreeFile>,
+}
+
+pub enum Node<'a> {
+    Folder(&'a Folder),
+    File(&'a TreeFile),
+}
+
+impl Folder {
+    /// Immediate subfolders, sorted by name
+    pub fn folders(&self) -> impl Iterator<Item = &Folder> { self.folders.values() }
+
+    /// Files directly in this folder, in archive order
+    pub fn files(&self) -> &[TreeFile] { &self.files }
+
+    /// Immediate children, folders first
+    pub fn children(&self) -> impl Iterator<Item = Node<'_>> {
+        self.folders().map(Node::Folder).chain(self.files.iter().map(Node::File))
+    }
+
+    pub fn folder(&self, name: &str) -> Option<&Folder> { self.folders.get(&name.to_lowercase()) }
+
+    /// This folder and every folder below it, depth first
+    pub fn walk(&self) -> Walk<'_> { Walk { stack: vec![self] } }
+
+    /// Every file below this folder
+    pub fn all_files(&self) -> impl Iterator<Item = &TreeFile> { self.walk().flat_map(|folder| folder.files.iter()) }
+
+    fn insert(&mut self, components: &[&str], file: TreeFile) {
+        match components.split_first() {
+            None => self.files.push(file),
+            Some((first, rest)) => {
+                let path = if self.path.is_empty() { first.to_string() } else { format!("{}\\{}", self.path, first) };
+                let folder = self.folders.entry(first.to_lowercase()).or_insert_with(|| Folder {
+                    name: first.to_string(),
+                    path,
+                    ..Default::default()
+                });
+                folder.insert(rest, file);
+            }
+        }
+    }
+
+    fn update_totals(&mut self) {
+        self.file_count = self.files.len();
+        self.compressed_size = self.files.iter().map(|file| file.compressed_size).sum();
+        self.uncompressed_size = self.files.iter().filter_map(|file| file.uncompressed_size).sum();
+        self.unknown_sizes = self.files.iter().filter(|file| file.uncompressed_size.is_none()).count();
+        for folder in self.folders.values_mut() {
+            folder.update_totals();
+            self.file_count += folder.file_count;
+            self.compressed_size += folder.compressed_size;
+            self.uncompressed_size += folder.uncompressed_size;
+            self.unknown_sizes += folder.unknown_sizes;
+        }
+    }
+}
+
+pub struct Walk<'a> {
+    stack: Vec<&'a Folder>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = &'a Folder;
+
+    fn next(&mut self) -> Option<&'a Folder> {
+        let folder = self.stack.pop()?;
+        self.stack.extend(folder.folders.values().rev());
+        Some(folder)
+    }
+}
+
+/// Folder hierarchy of an archive's flat entry names. Folders are matched case-insensitively with either separator.
+#[derive(Debug, Clone)]
+pub struct Tree {
+    root: Folder,
+}
+
+impl Tree {
+    /// Builds the tree, reading the LZMA header of every compressed entry for its uncompressed size. Entries whose
+    /// size can't be read are listed without one.
+    pub fn build<S: Storage>(iro: &mut IRO<S>) -> Self {
+        let mut root = Folder::default();
+        for idx in 0..iro.files.len() {
+            let uncompressed_size = iro.unpacked_size(idx).ok();
+            let entry = &iro.files[idx];
+            let mut components: Vec<&str> = entry.name.split(['\\', '/']).filter(|c| !c.is_empty()).collect();
+            let name = components.pop().unwrap_or_default().to_string();
+
+            root.insert(&components, TreeFile { name, entry: idx, compressed_size: entry.length, uncompressed_size });
+        }
+        root.update_totals();
+
+        Self { root }
+    }
+
+    pub fn root(&self) -> &Folder { &self.root }
+
+    /// The folder at `path`, e.g. `field\char`. An empty path is the root.
+    pub fn folder(&self, path: &str) -> Option<&Folder> {
+        path.split(['\\', '/']).filter(|c| !c.is_empty()).try_fold(&self.root, |folder, name| folder.folder(name))
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    imports::*,
+    iro::IRO,
+    iro_writer::HEADER_SIZE,
+    storage::Storage,
+};
+
+#[derive(Debug)]
+pub enum Problem {
+    /// The entry's data extends past the end of the archive
+    OutOfBounds { archive_size: u64 },
+    /// The entry's data partially overlaps another entry. Entries sharing exactly the same data are fine.
+    Overlaps { other: usize },
+    /// The entry's data overlaps the archive header or directory
+    OverlapsDirectory,
+    /// Reading or decompressing the entry failed, including data that doesn't decode to the size it declares
+    Unreadable(IroError),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::OutOfBounds { archive_size } => write!(f, "data lies outside the archive ({} bytes)", archive_size),
+            Problem::Overlaps { other } => write!(f, "data overlaps entry {}", other),
+            Problem::OverlapsDirectory => write!(f, "data overlaps the archive header or directory"),
+            Problem::Unreadable(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EntryReport {
+    pub entry:    usize,
+    pub name:     String,
+    pub problems: Vec<Problem>,
+}
+
+impl EntryReport {
+    pub fn is_ok(&self) -> bool { self.problems.is_empty() }
+}
+
+/// Result of [`IRO::verify`], one report per entry in directory order
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub entries: Vec<EntryReport>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool { self.entries.iter().all(EntryReport::is_ok) }
+
+    pub fn failed(&self) -> impl Iterator<Item = &EntryReport> { self.entries.iter().filter(|report| !report.is_ok()) }
+}
+
+impl<S: Storage> IRO<S> {
+    /// Checks that every entry lies within the archive, doesn't overlap the header, directory or other entries and
+    /// decodes to the size it declares. Every entry is read in full, compressed entries are decompressed.
+    pub fn verify(&mut self) -> VerifyReport {
+        let archive_size = self.archive_size();
+        let mut entries: Vec<EntryReport> = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(entry, file)| EntryReport { entry, name: file.name.clone(), problems: Vec::new() })
+            .collect();
+
+        // the header and the directory are reserved, no entry's data may lie in them
+        let directory = self.directory_range();
+        let reserved = [0..HEADER_SIZE, directory];
+        for (idx, file) in self.files.iter().enumerate() {
+            let end = file.offset.saturating_add(file.length);
+            if file.length > 0 && reserved.iter().any(|range| file.offset < range.end && range.start < end) {
+                entries[idx].problems.push(Problem::OverlapsDirectory);
+            }
+        }
+
+        let mut by_offset: Vec<usize> = (0..self.files.len()).filter(|&idx| self.files[idx].length > 0).collect();
+        by_offset.sort_by_key(|&idx| (self.files[idx].offset, self.files[idx].length));
+        // the entry reaching furthest so far, so an overlap with any earlier entry is caught, not just the previous one
+        let mut furthest: Option<(usize, u64)> = None;
+        for &idx in by_offset.iter() {
+            let file = &self.files[idx];
+            let end = file.offset.saturating_add(file.length);
+            if let Some((other, other_end)) = furthest {
+                let other_file = &self.files[other];
+                let same_data = other_file.offset == file.offset && other_file.length == file.length;
+                if file.offset < other_end && !same_data {
+                    entries[idx].problems.push(Problem::Overlaps { other });
+                }
+            }
+            if furthest.map(|(_, other_end)| end > other_end).unwrap_or(true) {
+                furthest = Some((idx, end));
+            }
+        }
+
+        for (idx, report) in entries.iter_mut().enumerate() {
+            let file = &self.files[idx];
+            if file.offset.checked_add(file.length).map(|end| end > archive_size).unwrap_or(true) {
+                report.problems.push(Problem::OutOfBounds { archive_size });
+                continue;
+            }
+
+            if let Err(err) = self.extract_to(io::sink(), idx) {
+                report.problems.push(Problem::Unreadable(err));
+            }
+        }
+
+        VerifyReport { entries }
+    }
+}
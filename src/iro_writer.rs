@@ -0,0 +1,350 @@
+use crate::{
+    imports::*,
+    iro::{
+        ArchiveKind, Compression, EntryFlags, RawEntryEnd, RawEntryStart, RawHeader, Version, ARCHIVE_FLAG_PATCH,
+        IRO_SIGNATURE,
+    },
+    iro_compress::{compress_lzma, CompressionPolicy, EntryInfo, StoreAll, DEFAULT_LZMA_PRESET, HEAD_SIZE},
+};
+use byteorder::WriteBytesExt;
+
+pub(crate) const HEADER_SIZE: u64 = size_of::<RawHeader>() as u64;
+
+fn end_size(version: Version) -> usize {
+    if version.has_wide_offsets() { size_of::<RawEntryEnd<U64<LE>>>() } else { size_of::<RawEntryEnd<U32<LE>>>() }
+}
+
+fn encode_name(name: &str) -> Vec<u8> { name.encode_utf16().flat_map(|c| c.to_le_bytes()).collect() }
+
+/// Size of the directory record for `name`, or `None` if the name does not fit in a record
+pub(crate) fn record_size(name: &str, version: Version) -> Option<usize> {
+    let name_size = name.encode_utf16().count() * size_of::<u16>();
+    let entry_size = size_of::<RawEntryStart>() + name_size + end_size(version);
+    if entry_size > u16::MAX as usize { None } else { Some(entry_size) }
+}
+
+pub(crate) fn write_header<W: Write>(
+    mut writer: W, version: Version, kind: ArchiveKind, num_entries: usize,
+) -> IroResult<()> {
+    let archive_flags = match kind {
+        ArchiveKind::Full => 0,
+        ArchiveKind::Patch => ARCHIVE_FLAG_PATCH,
+    };
+
+    writer.write_all(IRO_SIGNATURE)?;
+    writer.write_u32::<LE>(version.raw())?;
+    writer.write_u32::<LE>(archive_flags)?;
+    writer.write_u32::<LE>(HEADER_SIZE as u32)?;
+    writer.write_u32::<LE>(num_entries as u32)?;
+    Ok(())
+}
+
+pub(crate) fn write_record<W: Write>(
+    mut writer: W, version: Version, name: &str, flags: u32, offset: u64, length: u64,
+) -> IroResult<()> {
+    let name_bytes = encode_name(name);
+    let entry_size = size_of::<RawEntryStart>() + name_bytes.len() + end_size(version);
+    if entry_size > u16::MAX as usize {
+        return Err(IroError::NameTooLong(name.to_string()));
+    }
+    if length > u32::MAX as u64 || (!version.has_wide_offsets() && offset > u32::MAX as u64) {
+        return Err(IroError::ArchiveTooLarge);
+    }
+
+    writer.write_u16::<LE>(entry_size as u16)?;
+    writer.write_u16::<LE>(name_bytes.len() as u16)?;
+    writer.write_all(&name_bytes)?;
+    writer.write_u32::<LE>(flags)?;
+    if version.has_wide_offsets() {
+        writer.write_u64::<LE>(offset)?;
+    } else {
+        writer.write_u32::<LE>(offset as u32)?;
+    }
+    writer.write_u32::<LE>(length as u32)?;
+    Ok(())
+}
+
+/// Lays out an archive with the records exactly as given, for tests that need flags the writer wouldn't produce
+#[cfg(test)]
+pub(crate) fn raw_archive(version: Version, kind: ArchiveKind, entries: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_header(&mut out, version, kind, entries.len()).unwrap();
+    let mut offset = HEADER_SIZE + entries.iter().map(|(name, ..)| record_size(name, version).unwrap() as u64).sum::<u64>();
+    for (name, flags, data) in entries {
+        write_record(&mut out, version, name, *flags, offset, data.len() as u64).unwrap();
+        offset += data.len() as u64;
+    }
+    for (_, _, data) in entries {
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+/// An entry's data as handed to [`write_archive`]
+pub(crate) enum EntryData<'a> {
+    /// Uncompressed data of a known size, compressed according to the policy. The compression byte of `flags` is
+    /// replaced, the other bits are kept.
+    Plain { flags: EntryFlags, size: u64, reader: Box<dyn Read + 'a> },
+    /// Data written as it is under `flags`
+    Raw { flags: EntryFlags, reader: Box<dyn Read + 'a> },
+}
+
+/// The entries of an archive being written, opened one at a time
+pub(crate) trait EntrySource {
+    fn entry_count(&self) -> usize;
+
+    fn entry_name(&self, idx: usize) -> &str;
+
+    fn open_data(&mut self, idx: usize) -> IroResult<EntryData<'_>>;
+}
+
+/// Writes every entry of `source` in order. The directory is written last, so `writer` has to be seekable, and it
+/// is left at the end of the archive. Compressed data the policy rejects is overwritten by the stored data, if it
+/// was longer and belonged to the last entry some of it remains past the end of the archive.
+pub(crate) fn write_archive<W: Write + io::Seek, E: EntrySource>(
+    writer: W, version: Version, kind: ArchiveKind, policy: &dyn CompressionPolicy, lzma_preset: u32, source: &mut E,
+) -> IroResult<()> {
+    let mut writer = io::BufWriter::new(writer);
+    let start = writer.stream_position()?;
+    let names: Vec<String> = (0..source.entry_count()).map(|idx| source.entry_name(idx).to_string()).collect();
+
+    let mut directory_size = 0;
+    for name in names.iter() {
+        directory_size += record_size(name, version).ok_or_else(|| IroError::NameTooLong(name.clone()))? as u64;
+    }
+
+    let data_start = HEADER_SIZE + directory_size;
+    writer.seek(io::SeekFrom::Start(start + data_start))?;
+
+    let mut records = Vec::with_capacity(names.len());
+    let mut offset = data_start;
+    for (idx, name) in names.iter().enumerate() {
+        let (flags, length) = write_data(&mut writer, policy, lzma_preset, source, idx, name)?;
+        records.push((flags, offset, length));
+        offset += length;
+    }
+
+    writer.seek(io::SeekFrom::Start(start))?;
+    write_header(&mut writer, version, kind, names.len())?;
+    for (name, (flags, offset, length)) in names.iter().zip(records) {
+        write_record(&mut writer, version, name, flags, offset, length)?;
+    }
+    writer.seek(io::SeekFrom::Start(start + offset))?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Writes the entry's data, returning the flags and length of its record. The data is compressed as it is read and
+/// only read again if the policy rejects the result, then it is stored over the compressed data.
+fn write_data<W: Write + io::Seek, E: EntrySource>(
+    writer: &mut W, policy: &dyn CompressionPolicy, lzma_preset: u32, source: &mut E, idx: usize, name: &str,
+) -> IroResult<(u32, u64)> {
+    let (flags, size, mut reader) = match source.open_data(idx)? {
+        EntryData::Plain { flags, size, reader } => (flags, size, reader),
+        EntryData::Raw { flags, mut reader } => return Ok((flags.into(), io::copy(&mut reader, writer)?)),
+    };
+    let stored = |length| Ok((flags.with_compression(Compression::None).into(), length));
+
+    let mut head = Vec::with_capacity(HEAD_SIZE);
+    (&mut reader).take(HEAD_SIZE as u64).read_to_end(&mut head)?;
+    let info = EntryInfo { name, size, head: &head };
+
+    // the LZMA header only has room for a 32-bit unpacked size
+    if policy.choose(&info) == Compression::LZMA && size <= u32::MAX as u64 {
+        let start = writer.stream_position()?;
+        let length = compress_lzma(name, io::Cursor::new(&head).chain(reader), &mut *writer, size, lzma_preset)?;
+        if policy.accept(&info, length) {
+            return Ok((flags.with_compression(Compression::LZMA).into(), length));
+        }
+
+        writer.seek(io::SeekFrom::Start(start))?;
+        let mut reader = match source.open_data(idx)? {
+            EntryData::Plain { reader, .. } | EntryData::Raw { reader, .. } => reader,
+        };
+        return stored(io::copy(&mut reader, writer)?);
+    }
+
+    writer.write_all(&head)?;
+    stored(head.len() as u64 + io::copy(&mut reader, writer)?)
+}
+
+enum Source {
+    Bytes(Vec<u8>),
+    File(path::PathBuf),
+}
+
+impl Source {
+    fn size(&self) -> IroResult<u64> {
+        match self {
+            Source::Bytes(data) => Ok(data.len() as u64),
+            Source::File(path) => Ok(fs::metadata(path)?.len()),
+        }
+    }
+
+    fn open(&self) -> IroResult<Box<dyn Read + '_>> {
+        match self {
+            Source::Bytes(data) => Ok(Box::new(data.as_slice())),
+            Source::File(path) => Ok(Box::new(io::BufReader::new(fs::File::open(path)?))),
+        }
+    }
+}
+
+struct PendingEntry {
+    name:   String,
+    source: Source,
+}
+
+/// Builds an IRO archive from in-memory data and files on disk.
+///
+/// Entries are written in the order they were added, data is only read from disk while writing. Everything is
+/// stored uncompressed unless a compression policy is set.
+pub struct IroWriter {
+    version:     Version,
+    entries:     Vec<PendingEntry>,
+    policy:      Box<dyn CompressionPolicy>,
+    lzma_preset: u32,
+}
+
+impl IroWriter {
+    pub fn new(version: Version) -> Self {
+        Self { version, entries: Vec::new(), policy: Box::new(StoreAll), lzma_preset: DEFAULT_LZMA_PRESET }
+    }
+
+    pub fn set_compression_policy<P: CompressionPolicy + 'static>(&mut self, policy: P) { self.policy = Box::new(policy); }
+
+    /// LZMA preset from 0 (fastest) to 9 (smallest), defaults to [`DEFAULT_LZMA_PRESET`]
+    pub fn set_lzma_preset(&mut self, preset: u32) { self.lzma_preset = preset.min(9); }
+
+    pub fn add_bytes<N: Into<String>>(&mut self, name: N, data: Vec<u8>) {
+        self.entries.push(PendingEntry { name: name.into(), source: Source::Bytes(data) });
+    }
+
+    pub fn add_file<N: Into<String>, P: AsRef<path::Path>>(&mut self, name: N, path: P) {
+        self.entries.push(PendingEntry { name: name.into(), source: Source::File(path.as_ref().to_path_buf()) });
+    }
+
+    /// Adds every file below `dir`, named by its path relative to `dir` with `\` separators
+    pub fn add_dir<P: AsRef<path::Path>>(&mut self, dir: P) -> IroResult<()> {
+        let mut files = Vec::new();
+        collect_files(dir.as_ref(), &mut files)?;
+        files.sort();
+
+        for file in files {
+            let relative = file.strip_prefix(dir.as_ref()).unwrap_or(&file);
+            let name = relative.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>().join("\\");
+            self.add_file(name, file);
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    /// Writes the archive. The directory is written last, so `writer` has to be seekable. Write to a new or truncated
+    /// file, the policy rejecting the compressed data of the last entry can leave unused bytes after the archive.
+    pub fn write<W: Write + io::Seek>(&self, writer: W) -> IroResult<()> {
+        write_archive(writer, self.version, ArchiveKind::Full, &*self.policy, self.lzma_preset, &mut self.entries.as_slice())
+    }
+}
+
+impl EntrySource for &[PendingEntry] {
+    fn entry_count(&self) -> usize { self.len() }
+
+    fn entry_name(&self, idx: usize) -> &str { &self[idx].name }
+
+    fn open_data(&mut self, idx: usize) -> IroResult<EntryData<'_>> {
+        let source = &self[idx].source;
+        Ok(EntryData::Plain { flags: EntryFlags::default(), size: source.size()?, reader: source.open()? })
+    }
+}
+
+// symlinks to directories are skipped, following them could loop or add the same files under several names
+fn collect_files(dir: &path::Path, files: &mut Vec<path::PathBuf>) -> IroResult<()> {
+    for dir_entry in fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let (file_type, path) = (dir_entry.file_type()?, dir_entry.path());
+        if file_type.is_dir() {
+            collect_files(&path, files)?;
+        } else if !(file_type.is_symlink() && path.is_dir()) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{iro::IRO, iro_compress::LzmaAll};
+
+    const ENTRIES: &[(&str, &[u8])] = &[
+        ("empty.bin", b""),
+        ("one.bin", b"1"),
+        ("field\\char\\text.txt", b"some text that compresses, some text that compresses, some text that compresses"),
+        ("battle/ünïcödé.bin", &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+        ("also-empty", b""),
+    ];
+
+    fn write(version: Version, lzma: bool) -> Vec<u8> {
+        let mut writer = IroWriter::new(version);
+        if lzma {
+            writer.set_compression_policy(LzmaAll);
+        }
+        for (name, data) in ENTRIES {
+            writer.add_bytes(*name, data.to_vec());
+        }
+        let mut out = io::Cursor::new(Vec::new());
+        writer.write(&mut out).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn round_trip() {
+        for version in [Version::V0, Version::V1, Version::V2] {
+            for lzma in [false, true] {
+                let archive = write(version, lzma);
+                let mut iro = IRO::from_slice(&archive).unwrap();
+                assert_eq!((iro.version, iro.kind), (version, ArchiveKind::Full));
+                assert_eq!(iro.files.len(), ENTRIES.len());
+                for (idx, (name, data)) in ENTRIES.iter().enumerate() {
+                    let entry = iro.files[idx].clone();
+                    assert_eq!(entry.name, *name);
+                    let expected = if lzma { Compression::LZMA } else { Compression::None };
+                    assert_eq!((entry.compression, entry.flags.0), (expected, u32::from(expected)));
+                    let mut extracted = Vec::new();
+                    iro.extract_to(&mut extracted, idx).unwrap();
+                    assert_eq!(extracted, *data, "{:?} {} {}", version, lzma, name);
+                }
+                assert!(iro.verify().failed().next().is_none(), "{:?} {}", version, lzma);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_archive() {
+        let mut out = io::Cursor::new(Vec::new());
+        IroWriter::new(Version::V2).write(&mut out).unwrap();
+        assert_eq!(out.get_ref().len() as u64, HEADER_SIZE);
+        assert!(IRO::from_slice(out.get_ref()).unwrap().files.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn add_dir_skips_symlinked_directories() {
+        let dir = std::env::temp_dir().join(format!("moteria-add-dir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub").join("a.txt"), b"a").unwrap();
+        fs::write(dir.join("b.txt"), b"b").unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("sub").join("loop")).unwrap();
+        std::os::unix::fs::symlink(dir.join("b.txt"), dir.join("link.txt")).unwrap();
+
+        let mut writer = IroWriter::new(Version::V2);
+        writer.add_dir(&dir).unwrap();
+        let names: Vec<&str> = writer.entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["b.txt", "link.txt", "sub\\a.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
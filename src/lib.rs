@@ -1,11 +1,28 @@
 mod imports {
-    pub(crate) use anyhow::{anyhow, Result};
+    pub(crate) use anyhow::Result;
     pub(crate) use byteorder::LE;
     pub(crate) use std::{fs, io, io::prelude::*, path, fmt, mem::size_of};
     pub(crate) use widestring::UStr;
     pub(crate) use zerocopy::{byteorder::{U16, U32, U64}};
+    pub(crate) use crate::error::{IroError, IroResult};
 }
 
+pub mod entry_reader;
+pub mod error;
 pub mod iro;
+pub mod iro_compress;
+pub mod iro_diff;
+pub mod iro_extract;
+pub mod iro_glob;
 pub mod iro_mmap;
+pub mod iro_patch;
+pub mod iro_repack;
+pub mod iro_stats;
+pub mod iro_tree;
+pub mod iro_verify;
+pub mod iro_writer;
+mod lzs;
 pub mod mod_xml;
+pub mod storage;
+
+pub use error::{IroError, IroResult};
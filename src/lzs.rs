@@ -0,0 +1,183 @@
+//! FF7's LZSS variant: a 4 KiB ring buffer filled with zeroes and written from 0xfee, flag bytes whose bits (lowest
+//! first) mark literals, and back references holding an absolute 12-bit ring position and a 4-bit length minus 3.
+
+use crate::{imports::*, iro::Entry};
+
+const RING_SIZE: usize = 4096;
+const RING_START: usize = 0xfee;
+const MIN_MATCH: usize = 3;
+
+pub(crate) struct LzsReader<R> {
+    reader:    R,
+    ring:      Box<[u8; RING_SIZE]>,
+    ring_pos:  usize,
+    flags:     u8,
+    flags_left: u8,
+    copy_from: usize,
+    copy_left: usize,
+}
+
+impl<R: BufRead> LzsReader<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            ring: Box::new([0; RING_SIZE]),
+            ring_pos: RING_START,
+            flags: 0,
+            flags_left: 0,
+            copy_from: 0,
+            copy_left: 0,
+        }
+    }
+
+    fn next_input(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.reader.fill_buf()?.first().copied();
+        if byte.is_some() {
+            self.reader.consume(1);
+        }
+        Ok(byte)
+    }
+
+    fn push(&mut self, byte: u8) -> u8 {
+        self.ring[self.ring_pos] = byte;
+        self.ring_pos = (self.ring_pos + 1) % RING_SIZE;
+        byte
+    }
+
+    /// The next output byte, `None` once the input is used up
+    fn next_output(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            if self.copy_left > 0 {
+                let byte = self.ring[self.copy_from];
+                self.copy_from = (self.copy_from + 1) % RING_SIZE;
+                self.copy_left -= 1;
+                return Ok(Some(self.push(byte)));
+            }
+
+            if self.flags_left == 0 {
+                match self.next_input()? {
+                    Some(flags) => self.flags = flags,
+                    None => return Ok(None),
+                }
+                self.flags_left = 8;
+            }
+            let literal = self.flags & 1 != 0;
+            self.flags >>= 1;
+            self.flags_left -= 1;
+
+            // the last flag byte is padded, running out of input where the next item would start is the regular end
+            let first = match self.next_input()? {
+                Some(byte) => byte,
+                None => return Ok(None),
+            };
+            if literal {
+                return Ok(Some(self.push(first)));
+            }
+            let second = self.next_input()?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "compressed data ends inside a back reference")
+            })?;
+            self.copy_from = first as usize | ((second as usize & 0xf0) << 4);
+            self.copy_left = (second as usize & 0x0f) + MIN_MATCH;
+        }
+    }
+}
+
+impl<R: BufRead> Read for LzsReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.next_output()? {
+                Some(byte) => buf[read] = byte,
+                None => break,
+            }
+            read += 1;
+        }
+        Ok(read)
+    }
+}
+
+/// Sets up a decoder for an LZS entry. FF7's own `.lzs` files start with the compressed size, IRO entries have been
+/// seen both with and without it, so it is skipped if it matches the entry's length.
+pub(crate) fn lzs_reader<R: BufRead>(mut reader: R, entry: &Entry) -> io::Result<LzsReader<io::Chain<io::Cursor<Vec<u8>>, R>>> {
+    let mut prefix = Vec::with_capacity(4);
+    (&mut reader).take(4).read_to_end(&mut prefix)?;
+    if prefix.len() == 4 && u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as u64 == entry.length - 4 {
+        prefix.clear();
+    }
+
+    Ok(LzsReader::new(io::Cursor::new(prefix).chain(reader)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iro::{Compression, EntryFlags};
+
+    fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+        let entry = Entry {
+            offset:      0,
+            length:      data.len() as u64,
+            name:        "test.lzs".into(),
+            compression: Compression::LZS,
+            flags:       EntryFlags(u32::from(Compression::LZS)),
+        };
+        let mut out = Vec::new();
+        lzs_reader(data, &entry)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn with_prefix(data: &[u8]) -> Vec<u8> {
+        let mut prefixed = (data.len() as u32).to_le_bytes().to_vec();
+        prefixed.extend_from_slice(data);
+        prefixed
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(decode(b"\xffAERITH!!").unwrap(), b"AERITH!!");
+        // a partial last group, the rest of the flag byte is padding
+        assert_eq!(decode(b"\xffFF7").unwrap(), b"FF7");
+        assert_eq!(decode(b"").unwrap(), b"");
+    }
+
+    #[test]
+    fn back_reference_into_zeroed_ring() {
+        // position 0 has never been written and still holds zeroes
+        assert_eq!(decode(b"\x00\x00\x00").unwrap(), [0; 3]);
+        assert_eq!(decode(b"\x01x\x00\x01").unwrap(), b"x\0\0\0\0");
+    }
+
+    #[test]
+    fn back_reference_to_output() {
+        // 0xfee is where the first byte was written
+        assert_eq!(decode(b"\x07ABC\xee\xf0").unwrap(), b"ABCABC");
+        // a reference may overlap the bytes it produces
+        assert_eq!(decode(b"\x01A\xee\xf2").unwrap(), b"AAAAAA");
+        // 18 bytes is the longest reference
+        assert_eq!(decode(b"\x01-\xee\xff").unwrap(), [b'-'; 19]);
+    }
+
+    #[test]
+    fn truncated_back_reference() {
+        let err = decode(b"\x01A\xee").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(decode(b"\x00\x12").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn size_prefix_is_skipped() {
+        for data in [&b"\xffAERITH!!"[..], b"\x07ABC\xee\xf0", b"\x00\x00\x00"] {
+            assert_eq!(decode(&with_prefix(data)).unwrap(), decode(data).unwrap());
+        }
+    }
+
+    #[test]
+    fn data_without_prefix_is_decoded_as_is() {
+        // the first four bytes aren't the remaining length, so they are compressed data
+        assert_eq!(decode(b"\xffABCDEFG").unwrap(), b"ABCDEFG");
+        // a prefix that doesn't match the length is treated as data too, here a flag byte marking only literals
+        let mut prefixed = with_prefix(b"\xffAB");
+        prefixed[0] = 0xff;
+        assert_eq!(decode(&prefixed).unwrap(), b"\0\0\0\xffAB");
+    }
+}
@@ -1,3 +1,6 @@
+// WIP: the parsed fields are only used via Debug for now
+#![allow(dead_code)]
+
 use crate::imports::*;
 
 #[derive(Debug, Default)]
@@ -58,7 +61,7 @@ fn parse_config_option<'a>(parent: roxmltree::Node<'a, '_>) -> Result<ConfigOpti
     unimplemented!()
 }
 
-fn parse_mod_folder<'a>(node: roxmltree::Node<'a, '_>) -> Result<()> {
+fn parse_mod_folder<'a>(_node: roxmltree::Node<'a, '_>) -> Result<()> {
     unimplemented!()
 }
 
@@ -0,0 +1,113 @@
+use crate::imports::*;
+
+/// Random access to the bytes of an archive
+pub trait Storage {
+    fn size(&self) -> u64;
+
+    /// Returns a reader over `length` bytes starting at `offset`. Callers check the range against `size` first.
+    fn read_range(&mut self, offset: u64, length: u64) -> io::Result<Box<dyn BufRead + '_>>;
+
+    /// The whole archive, if the storage keeps it in memory
+    fn as_bytes(&self) -> Option<&[u8]> { None }
+}
+
+/// Storage kept in memory that can be read through a shared reference, so one archive can be extracted from several
+/// threads at once
+pub trait SharedStorage: Storage + Sync {
+    fn bytes(&self) -> &[u8];
+}
+
+/// Storage over any seekable reader, e.g. a file or a decompressed stream from another container
+pub struct ReaderStorage<R> {
+    reader: io::BufReader<R>,
+    size:   u64,
+}
+
+pub type FileStorage = ReaderStorage<fs::File>;
+
+impl<R: Read + Seek> ReaderStorage<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let size = reader.seek(io::SeekFrom::End(0))?;
+        Ok(Self { reader: io::BufReader::with_capacity(132768, reader), size })
+    }
+
+    pub fn into_inner(self) -> R { self.reader.into_inner() }
+}
+
+impl FileStorage {
+    pub fn open(path: &path::Path) -> io::Result<Self> { Self::new(fs::File::open(path)?) }
+}
+
+impl<R: Read + Seek> Storage for ReaderStorage<R> {
+    fn size(&self) -> u64 { self.size }
+
+    fn read_range(&mut self, offset: u64, length: u64) -> io::Result<Box<dyn BufRead + '_>> {
+        // seeking relative to the current position keeps the buffer if the target is inside it
+        let position = self.reader.stream_position()?;
+        self.reader.seek_relative(offset as i64 - position as i64)?;
+        Ok(Box::new((&mut self.reader).take(length)))
+    }
+}
+
+pub struct MmapStorage {
+    mmap: memmap::Mmap,
+}
+
+impl MmapStorage {
+    pub fn open(path: &path::Path) -> io::Result<Self> {
+        let file = fs::File::open(path)?;
+        let mmap = unsafe { memmap::Mmap::map(&file)? };
+        Ok(Self { mmap })
+    }
+}
+
+impl Storage for MmapStorage {
+    fn size(&self) -> u64 { self.mmap.len() as u64 }
+
+    fn read_range(&mut self, offset: u64, length: u64) -> io::Result<Box<dyn BufRead + '_>> {
+        Ok(Box::new(slice_range(&self.mmap, offset, length)?))
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> { Some(&self.mmap) }
+}
+
+impl SharedStorage for MmapStorage {
+    fn bytes(&self) -> &[u8] { &self.mmap }
+}
+
+fn slice_range(data: &[u8], offset: u64, length: u64) -> io::Result<&[u8]> {
+    let start = offset as usize;
+    let end = offset.checked_add(length).map(|end| end as usize);
+    match end {
+        Some(end) if end <= data.len() => Ok(&data[start..end]),
+        _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+    }
+}
+
+impl Storage for &[u8] {
+    fn size(&self) -> u64 { self.len() as u64 }
+
+    fn read_range(&mut self, offset: u64, length: u64) -> io::Result<Box<dyn BufRead + '_>> {
+        Ok(Box::new(slice_range(self, offset, length)?))
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> { Some(self) }
+}
+
+impl SharedStorage for &[u8] {
+    fn bytes(&self) -> &[u8] { self }
+}
+
+impl Storage for Vec<u8> {
+    fn size(&self) -> u64 { self.len() as u64 }
+
+    fn read_range(&mut self, offset: u64, length: u64) -> io::Result<Box<dyn BufRead + '_>> {
+        Ok(Box::new(slice_range(self, offset, length)?))
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> { Some(self) }
+}
+
+impl SharedStorage for Vec<u8> {
+    fn bytes(&self) -> &[u8] { self }
+}